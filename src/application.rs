@@ -1,10 +1,15 @@
 //! Sonarr Plex Cleaner Cli Abscissa Application
 
-use crate::{commands::SonarrPlexCleanerCliCommand, config::SonarrPlexCleanerCliConfig};
+use crate::{
+    commands::{default_config_path, SonarrPlexCleanerCliCommand},
+    config::SonarrPlexCleanerCliConfig,
+};
 use abscissa_core::{
-    application, config, logging, Application, EntryPoint, FrameworkError, StandardPaths,
+    application, config, err, log::LevelFilter, terminal::component::Terminal, Application,
+    Component, Configurable, EntryPoint, FrameworkError, FrameworkErrorKind, StandardPaths,
 };
 use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
 
 lazy_static! {
     /// Application state
@@ -36,10 +41,21 @@ pub struct SonarrPlexCleanerCliApplication {
     /// Application configuration.
     config: Option<SonarrPlexCleanerCliConfig>,
 
+    /// The config file given with the global `-c` option, if any.
+    config_path: Option<PathBuf>,
+
     /// Application state.
     state: application::State<Self>,
 }
 
+impl SonarrPlexCleanerCliApplication {
+    /// Location of the config file: the one given with `-c`, or else
+    /// the default location.
+    pub fn config_path(&self) -> PathBuf {
+        self.config_path.clone().unwrap_or_else(default_config_path)
+    }
+}
+
 /// Initialize a new application instance.
 ///
 /// By default no configuration is loaded, and the framework state is
@@ -48,6 +64,7 @@ impl Default for SonarrPlexCleanerCliApplication {
     fn default() -> Self {
         Self {
             config: None,
+            config_path: None,
             state: application::State::default(),
         }
     }
@@ -78,16 +95,48 @@ impl Application for SonarrPlexCleanerCliApplication {
         &mut self.state
     }
 
+    /// Initialize the application: register the components, then load
+    /// the config file. `init` writes the config file, so it never
+    /// loads it, not even when `-c` points at an existing one.
+    fn init(&mut self, command: &Self::Cmd) -> Result<(), FrameworkError> {
+        self.config_path = command.config.clone();
+        self.register_components(command)?;
+        if let Some(SonarrPlexCleanerCliCommand::Init(_)) = &command.command {
+            return Ok(());
+        }
+        let config = command
+            .config_path()
+            .map(|path| self.load_config(&path))
+            .transpose()?;
+        match config {
+            Some(config) => self.after_config(command.process_config(config)?),
+            None => Ok(()),
+        }
+    }
+
     /// Register all components used by this application.
     ///
-    /// If you would like to add additional components to your application
-    /// beyond the default ones provided by the framework, this is the place
-    /// to do so.
+    /// The framework's logging component only writes text, so it is
+    /// replaced by our own logger, which can also write JSON.
     fn register_components(&mut self, command: &Self::Cmd) -> Result<(), FrameworkError> {
-        let components = self.framework_components(command)?;
+        let level = if command.verbose {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        };
+        crate::logs::init(level).map_err(|e| err!(FrameworkErrorKind::ComponentError, e))?;
+        let components: Vec<Box<dyn Component<Self>>> =
+            vec![Box::new(Terminal::new(self.term_colors(command)))];
         self.state.components.register(components)
     }
 
+    /// Load the configuration file, applying the overrides from
+    /// `SPC_*` environment variables.
+    fn load_config(&mut self, path: &Path) -> Result<Self::Cfg, FrameworkError> {
+        crate::config::load_with_overrides(path, std::env::vars())
+            .map_err(|e| err!(FrameworkErrorKind::ConfigError, e))
+    }
+
     /// Post-configuration lifecycle callback.
     ///
     /// Called regardless of whether config is loaded to indicate this is the
@@ -96,16 +145,8 @@ impl Application for SonarrPlexCleanerCliApplication {
     fn after_config(&mut self, config: Self::Cfg) -> Result<(), FrameworkError> {
         // Configure components
         self.state.components.after_config(&config)?;
+        crate::logs::set_format(config.logging.format);
         self.config = Some(config);
         Ok(())
     }
-
-    /// Get logging configuration from command-line options
-    fn logging_config(&self, command: &EntryPoint<SonarrPlexCleanerCliCommand>) -> logging::Config {
-        if command.verbose {
-            logging::Config::verbose()
-        } else {
-            logging::Config::default()
-        }
-    }
 }
@@ -0,0 +1,185 @@
+//! A record of the changes the cleaner made.
+//!
+//! Every deletion and every retain tag that the cleaner applies is
+//! appended as a line of JSON to the audit log file, along with the
+//! changes that Sonarr reports through its webhooks.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::plan::PlanItem;
+use crate::services::sonarr::{Season, Series};
+
+/// What happened to an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A season was unmonitored and its files deleted.
+    Deleted,
+
+    /// A season was unmonitored, but only some of its files were
+    /// deleted.
+    PartiallyDeleted,
+
+    /// A series was tagged with the retain tag.
+    Retained,
+
+    /// Sonarr imported new files for a season.
+    Downloaded,
+
+    /// Sonarr deleted files of a season.
+    FilesDeleted,
+
+    /// A series was removed from Sonarr.
+    SeriesDeleted,
+
+    /// A warning went out that a season will soon be deleted.
+    Warned,
+}
+
+/// A single entry in the audit log.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    /// When the change was made.
+    pub time: DateTime<Utc>,
+
+    /// Who made the change: the subcommand that ran, or the web UI user.
+    pub actor: String,
+
+    /// What happened.
+    pub event: Event,
+
+    /// Sonarr ID of the series.
+    pub series_id: u32,
+
+    /// Title of the series.
+    pub title: String,
+
+    /// Number of the affected season, if the change was for a single season.
+    pub season: Option<u32>,
+
+    /// Space freed up by the change.
+    #[serde(default)]
+    pub size_bytes: u128,
+}
+
+impl Entry {
+    /// Creates an entry for a change to a whole series.
+    pub fn series(actor: &str, event: Event, series: &Series) -> Entry {
+        Entry {
+            time: Utc::now(),
+            actor: actor.to_string(),
+            event,
+            series_id: series.id,
+            title: series.title.clone(),
+            season: None,
+            size_bytes: 0,
+        }
+    }
+
+    /// Creates an entry for a change to a single season.
+    pub fn season(actor: &str, event: Event, series: &Series, season: &Season) -> Entry {
+        Entry {
+            season: Some(season.season_number),
+            size_bytes: season.statistics.size_on_disk,
+            ..Entry::series(actor, event, series)
+        }
+    }
+
+    /// Creates an entry for a season in the plan.
+    pub fn plan_item(actor: &str, event: Event, item: &PlanItem) -> Entry {
+        Entry {
+            time: Utc::now(),
+            actor: actor.to_string(),
+            event,
+            series_id: item.series_id,
+            title: item.title.clone(),
+            season: Some(item.season),
+            size_bytes: item.size_bytes,
+        }
+    }
+}
+
+/// An append-only audit log, stored as JSON lines.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Opens the audit log at `path`.
+    pub fn new(path: PathBuf) -> AuditLog {
+        AuditLog { path }
+    }
+
+    /// Appends an entry to the log.
+    pub fn record(&self, entry: &Entry) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        // One write per entry, so that entries recorded at the same
+        // time by the web server's workers don't get interleaved.
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns all entries, oldest first.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        BufReader::new(file)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+
+    /// Returns the `count` most recent entries, newest first.
+    pub fn recent(&self, count: usize) -> Result<Vec<Entry>> {
+        let mut entries = self.entries()?;
+        entries.reverse();
+        entries.truncate(count);
+        Ok(entries)
+    }
+
+    /// Returns the (series ID, season number) of every season that a
+    /// warning went out for since it was last deleted.
+    pub fn warned_seasons(&self) -> Result<HashSet<(u32, u32)>> {
+        let mut warned = HashSet::new();
+        for entry in self.entries()? {
+            if let Some(season) = entry.season {
+                match entry.event {
+                    Event::Warned => {
+                        warned.insert((entry.series_id, season));
+                    }
+                    Event::Deleted => {
+                        warned.remove(&(entry.series_id, season));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(warned)
+    }
+
+    /// Returns the most recent time that the cleaner deleted a season.
+    pub fn last_deletion(&self, series_id: u32, season: u32) -> Result<Option<Entry>> {
+        Ok(self.entries()?.into_iter().rev().find(|e| {
+            e.event == Event::Deleted && e.series_id == series_id && e.season == Some(season)
+        }))
+    }
+}
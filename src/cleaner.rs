@@ -0,0 +1,578 @@
+//! Running the cleanup against the configured servers.
+//!
+//! A [`Cleaner`] holds on to the API clients and the resolved
+//! retention policy, so that it can be run repeatedly without
+//! looking up users and tags again.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use abscissa_core::log::Level;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use futures::try_join;
+use serde::Serialize;
+
+use crate::audit::{AuditLog, Entry, Event};
+use crate::config::{RefreshSettings, SonarrPlexCleanerCliConfig};
+use crate::logs::{self, SeasonFields};
+use crate::metrics;
+use crate::plan::{self, format_size, Decision, PlanItem, Policy, Upcoming, Verdict};
+use crate::prelude::*;
+use crate::services::sonarr::{self, EpisodeFile, Season, SeasonDeletion, Series};
+use crate::services::viewer::{SeasonKey, ViewerClient, WatchState};
+
+/// What to do with a season that is eligible for deletion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Unmonitor the season and delete its files.
+    Delete,
+
+    /// Only report that the season would be deleted.
+    DryRun,
+
+    /// Leave the season alone.
+    Skip,
+
+    /// Leave the season alone and tag its series with the retain tag.
+    Retain,
+
+    /// Leave the season alone and stop processing further seasons.
+    Stop,
+}
+
+/// What happened during a run of the cleaner.
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    /// Seasons that were deleted.
+    pub deleted: Vec<PlanItem>,
+
+    /// Seasons of which only some files were deleted, with the size
+    /// of those files.
+    pub partially_deleted: Vec<PlanItem>,
+
+    /// Seasons that would have been deleted, if this wasn't a dry run.
+    pub would_delete: Vec<PlanItem>,
+
+    /// Seasons that were eligible for deletion, but were skipped.
+    pub skipped: Vec<PlanItem>,
+
+    /// Errors that happened while changing seasons.
+    pub errors: Vec<String>,
+
+    /// Seasons that will soon become eligible for deletion, and that
+    /// weren't warned about before.
+    pub upcoming: Vec<Upcoming>,
+}
+
+impl RunSummary {
+    /// Number of bytes freed by the (partially) deleted seasons.
+    pub fn bytes_freed(&self) -> u128 {
+        self.deleted
+            .iter()
+            .chain(self.partially_deleted.iter())
+            .map(|item| item.size_bytes)
+            .sum()
+    }
+
+    /// True if nothing happened that is worth reporting.
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty()
+            && self.partially_deleted.is_empty()
+            && self.would_delete.is_empty()
+            && self.skipped.is_empty()
+            && self.errors.is_empty()
+    }
+
+    fn error(&mut self, error: anyhow::Error) {
+        error!("{}", error);
+        self.errors.push(error.to_string());
+    }
+}
+
+/// The error returned when another cleanup run holds the lock file.
+#[derive(Debug)]
+pub struct RunInProgress(PathBuf);
+
+impl fmt::Display for RunInProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "another run holds the lock on {}", self.0.display())
+    }
+}
+
+impl std::error::Error for RunInProgress {}
+
+/// A lock on a file, held while the cleaner changes seasons, so that
+/// runs of the daemon, `tv` and the web server never overlap.
+struct RunLock(File);
+
+impl RunLock {
+    /// Locks the file at `path`, or fails with [`RunInProgress`] if
+    /// someone else holds the lock.
+    fn acquire(path: &Path) -> Result<RunLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|e| anyhow!("Could not open {}: {}", path.display(), e))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(RunLock(file)),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Err(RunInProgress(path.to_path_buf()).into())
+            }
+            Err(e) => Err(anyhow!("Could not lock {}: {}", path.display(), e)),
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// How much of a season [`Cleaner::delete`] got rid of.
+enum Deletion {
+    /// All of the season's files.
+    Complete,
+
+    /// Some of the season's files, which took up `freed` bytes, before
+    /// `error` stopped the deletion.
+    Partial { freed: u128, error: anyhow::Error },
+}
+
+/// Returns the seasons of `serieses` that are eligible for deletion.
+fn eligible(
+    policy: &Policy,
+    serieses: &[Series],
+    watched: &HashMap<SeasonKey, WatchState>,
+) -> Vec<PlanItem> {
+    plan::evaluate(policy, serieses, watched, Utc::now())
+        .iter()
+        .filter(|d| d.is_eligible())
+        .map(PlanItem::from)
+        .collect()
+}
+
+/// The API clients and policy needed to clean up TV seasons.
+pub struct Cleaner {
+    /// The Sonarr API client.
+    pub sonarr: sonarr::SonarrClient,
+
+    /// The media server that knows about watched states.
+    pub viewer: ViewerClient,
+
+    /// The retention policy.
+    pub policy: Policy,
+
+    /// Where changes get recorded.
+    pub audit: AuditLog,
+
+    /// What to tell the media server after deleting files.
+    pub refresh: RefreshSettings,
+
+    /// The file that is locked while seasons are changed.
+    lock_file: PathBuf,
+}
+
+impl Cleaner {
+    /// Sets up API clients and resolves the retention policy.
+    pub async fn from_config(config: &SonarrPlexCleanerCliConfig) -> Result<Cleaner> {
+        let sonarr = sonarr::SonarrClient::from_config(&config.tv)
+            .map_err(|e| anyhow!("Could not set up sonarr client: {}", e))?;
+        let (policy, viewer) = try_join!(
+            Policy::from_config(&config.retention, &sonarr),
+            ViewerClient::from_config(&config.viewer),
+        )?;
+        let audit = AuditLog::new(config.audit.path.clone());
+        Ok(Cleaner {
+            sonarr,
+            viewer,
+            policy,
+            audit,
+            refresh: config.refresh.clone(),
+            lock_file: config.daemon.lock_file.clone(),
+        })
+    }
+
+    /// Evaluates all seasons and asks `choose` what to do with each
+    /// season that is eligible for deletion. Changes are recorded in
+    /// the audit log as made by `actor`. In a `dry_run`, seasons that
+    /// will soon be eligible are only logged, so that the warnings
+    /// about them still go out once files actually get deleted.
+    ///
+    /// Fails with [`RunInProgress`] if another run is changing seasons
+    /// at the same time. Failures to change a single season don't
+    /// stop the run, they are collected in the returned summary
+    /// instead.
+    pub async fn run(
+        &self,
+        actor: &str,
+        dry_run: bool,
+        choose: impl FnMut(&Decision<'_>, &[&EpisodeFile]) -> Action,
+    ) -> Result<RunSummary> {
+        let (watched, serieses) = try_join!(self.viewer.all_watch_states(), self.all_series())?;
+        self.run_on(actor, dry_run, &serieses, &watched, choose)
+            .await
+    }
+
+    /// Like [`Cleaner::run`], but only evaluates the series with the
+    /// given title, against the watch states in `watched`.
+    pub async fn run_series(
+        &self,
+        actor: &str,
+        dry_run: bool,
+        title: &str,
+        watched: &HashMap<SeasonKey, WatchState>,
+        choose: impl FnMut(&Decision<'_>, &[&EpisodeFile]) -> Action,
+    ) -> Result<RunSummary> {
+        let serieses: Vec<Series> = self
+            .all_series()
+            .await?
+            .into_iter()
+            .filter(|s| s.title == title)
+            .collect();
+        if serieses.is_empty() {
+            warn!("No series named {:?} in sonarr", title);
+        }
+        self.run_on(actor, dry_run, &serieses, watched, choose)
+            .await
+    }
+
+    /// Fetches all series from Sonarr.
+    pub async fn all_series(&self) -> Result<Vec<Series>> {
+        self.sonarr
+            .fetch_all_series()
+            .await
+            .map_err(|e| anyhow!("sonarr: fetching serieses: {}", e))
+    }
+
+    async fn run_on(
+        &self,
+        actor: &str,
+        dry_run: bool,
+        serieses: &[Series],
+        watched: &HashMap<SeasonKey, WatchState>,
+        mut choose: impl FnMut(&Decision<'_>, &[&EpisodeFile]) -> Action,
+    ) -> Result<RunSummary> {
+        let _lock = RunLock::acquire(&self.lock_file)?;
+        let mut summary = RunSummary::default();
+        let now = Utc::now();
+        let service = self.viewer.service_name();
+        let decisions = plan::evaluate(&self.policy, serieses, watched, now);
+        for decision in decisions.iter() {
+            decision.log(service);
+            metrics::SEASONS_EVALUATED.inc();
+            match &decision.verdict {
+                Verdict::Delete => metrics::SEASONS_ELIGIBLE.inc(),
+                Verdict::Keep(reason) => metrics::SEASONS_KEPT
+                    .with_label_values(&[reason.label()])
+                    .inc(),
+            }
+        }
+        if let Err(e) = self.warn_upcoming(actor, dry_run, &decisions, now, &mut summary) {
+            summary.error(e);
+        }
+
+        let mut touched: Vec<&Series> = vec![];
+        'series: for (series, seasons) in plan::eligible_by_series(&decisions) {
+            let series_files = match self.sonarr.fetch_episode_files(series.id).await {
+                Ok(files) => files,
+                Err(e) => {
+                    summary.error(anyhow!("fetching files for {}: {}", series.title, e));
+                    continue;
+                }
+            };
+
+            for decision in seasons {
+                let season = decision.season;
+                let season_files: Vec<&EpisodeFile> = series_files
+                    .iter()
+                    .filter(|f| f.season_number == season.season_number)
+                    .collect();
+                let action = choose(decision, &season_files);
+                match action {
+                    Action::Delete | Action::DryRun => {}
+                    Action::Skip => {
+                        metrics::SEASONS_KEPT.with_label_values(&["skipped"]).inc();
+                        summary.skipped.push(decision.into());
+                        continue;
+                    }
+                    Action::Retain => {
+                        metrics::SEASONS_KEPT.with_label_values(&["skipped"]).inc();
+                        summary.skipped.push(decision.into());
+                        if let Err(e) = self.retain(actor, series).await {
+                            summary.error(e);
+                        }
+                        continue 'series;
+                    }
+                    Action::Stop => break 'series,
+                }
+                logs::season(
+                    Level::Info,
+                    &SeasonFields::decision(decision, service),
+                    format_args!(
+                        "delete {} files: {} S{:02}: {}",
+                        season_files.len(),
+                        series.title,
+                        season.season_number,
+                        format_size(season.statistics.size_on_disk),
+                    ),
+                );
+                if action == Action::DryRun {
+                    summary.would_delete.push(decision.into());
+                    continue;
+                }
+                // even a failed deletion may have removed some files:
+                if !touched.iter().any(|s| s.id == series.id) {
+                    touched.push(series);
+                }
+                match self.delete(actor, series, season, &season_files).await {
+                    Ok(Deletion::Complete) => summary.deleted.push(decision.into()),
+                    Ok(Deletion::Partial { freed, error }) => {
+                        summary.partially_deleted.push(PlanItem {
+                            size_bytes: freed,
+                            ..PlanItem::from(decision)
+                        });
+                        summary.error(error);
+                    }
+                    Err(e) => summary.error(e),
+                }
+            }
+        }
+        self.refresh_viewer(&touched).await;
+        Ok(summary)
+    }
+
+    /// Asks the media server to rescan the series whose files were
+    /// deleted. Failures are only logged, since the media server
+    /// catches up on its next scheduled scan anyway.
+    async fn refresh_viewer(&self, serieses: &[&Series]) {
+        if !self.refresh.library {
+            return;
+        }
+        for series in serieses.iter() {
+            let refreshed = self
+                .viewer
+                .refresh_series(
+                    &series.title,
+                    series.path.as_deref(),
+                    self.refresh.empty_plex_trash,
+                )
+                .await;
+            if let Err(e) = refreshed {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    /// Logs the seasons that will soon become eligible for deletion,
+    /// and unless this is a `dry_run`, records the ones that weren't
+    /// warned about yet and adds them to `summary`.
+    fn warn_upcoming(
+        &self,
+        actor: &str,
+        dry_run: bool,
+        decisions: &[Decision<'_>],
+        now: DateTime<Utc>,
+        summary: &mut RunSummary,
+    ) -> Result<()> {
+        let upcoming = plan::upcoming(&self.policy, decisions, now);
+        if upcoming.is_empty() {
+            return Ok(());
+        }
+        let warned = if dry_run {
+            Default::default()
+        } else {
+            self.audit.warned_seasons()?
+        };
+        for season in upcoming {
+            logs::season(
+                Level::Info,
+                &SeasonFields::item(&season.item, self.viewer.service_name()),
+                format_args!(
+                    "{} S{:02} will be eligible for deletion in {} days",
+                    season.item.title,
+                    season.item.season,
+                    (season.deletable_at - now).num_days()
+                ),
+            );
+            if !dry_run && !warned.contains(&(season.item.series_id, season.item.season)) {
+                self.audit
+                    .record(&Entry::plan_item(actor, Event::Warned, &season.item))?;
+                summary.upcoming.push(season);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the seasons that are currently eligible for deletion.
+    pub async fn plan(&self) -> Result<Vec<PlanItem>> {
+        let (watched, serieses) = try_join!(self.viewer.all_watch_states(), self.all_series())?;
+        Ok(eligible(&self.policy, &serieses, &watched))
+    }
+
+    /// Returns the seasons that are eligible for deletion, according
+    /// to the watch states in `watched`.
+    pub async fn plan_with(
+        &self,
+        watched: &HashMap<SeasonKey, WatchState>,
+    ) -> Result<Vec<PlanItem>> {
+        Ok(eligible(&self.policy, &self.all_series().await?, watched))
+    }
+
+    /// Deletes a single season, if it is still eligible for deletion
+    /// according to the watch states in `watched`. Fails with
+    /// [`RunInProgress`] while another run is changing seasons.
+    pub async fn delete_season(
+        &self,
+        actor: &str,
+        series_id: u32,
+        season_number: u32,
+        watched: &HashMap<SeasonKey, WatchState>,
+    ) -> Result<()> {
+        let _lock = RunLock::acquire(&self.lock_file)?;
+        let series: Series = self
+            .sonarr
+            .fetch_series(series_id)
+            .await
+            .map_err(|e| anyhow!("sonarr: fetching series {}: {}", series_id, e))?;
+        let serieses = vec![series];
+        let decisions = plan::evaluate(&self.policy, &serieses, watched, Utc::now());
+        let decision = decisions
+            .iter()
+            .find(|d| d.season.season_number == season_number)
+            .ok_or_else(|| anyhow!("{} has no season {}", serieses[0].title, season_number))?;
+        if !decision.is_eligible() {
+            return Err(anyhow!(
+                "{} S{:02} is not eligible for deletion: {:?}",
+                decision.series.title,
+                season_number,
+                decision.verdict
+            ));
+        }
+        let series_files = self
+            .sonarr
+            .fetch_episode_files(series_id)
+            .await
+            .map_err(|e| anyhow!("fetching files for {}: {}", decision.series.title, e))?;
+        let season_files: Vec<&EpisodeFile> = series_files
+            .iter()
+            .filter(|f| f.season_number == season_number)
+            .collect();
+        match self
+            .delete(actor, decision.series, decision.season, &season_files)
+            .await?
+        {
+            Deletion::Complete => Ok(()),
+            Deletion::Partial { error, .. } => Err(error),
+        }
+    }
+
+    /// Tags a series with the retain tag, so it doesn't get cleaned up.
+    pub async fn retain_series(&self, actor: &str, series_id: u32) -> Result<()> {
+        let series: Series = self
+            .sonarr
+            .fetch_series(series_id)
+            .await
+            .map_err(|e| anyhow!("sonarr: fetching series {}: {}", series_id, e))?;
+        self.retain(actor, &series).await
+    }
+
+    async fn retain(&self, actor: &str, series: &Series) -> Result<()> {
+        let (name, id) = self
+            .policy
+            .retain_tag
+            .as_ref()
+            .ok_or_else(|| anyhow!("No retain tag configured"))?;
+        info!("Tagging {} with {:?}", series.title, name);
+        self.sonarr
+            .tag_series(series.id, *id)
+            .await
+            .map_err(|e| anyhow!("Tagging {} with {:?}: {}", series.title, name, e))?;
+        self.audit
+            .record(&Entry::series(actor, Event::Retained, series))
+    }
+
+    /// Unmonitors `season` and deletes its `files`, recording the
+    /// deletion in the audit log and in the metrics. Fails if no files
+    /// were deleted.
+    async fn delete(
+        &self,
+        actor: &str,
+        series: &Series,
+        season: &Season,
+        files: &[&EpisodeFile],
+    ) -> Result<Deletion> {
+        self.sonarr
+            .unmonitor_season(series.id, season.season_number)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Unmonitoring season {} S{:02}: {}",
+                    series.title,
+                    season.season_number,
+                    e
+                )
+            })?;
+        match self.sonarr.delete_season_files(series.id, files).await {
+            SeasonDeletion::Complete => {
+                metrics::SEASONS_DELETED.inc();
+                metrics::BYTES_FREED.inc_by(season.statistics.size_on_disk as u64);
+                // the files are gone either way:
+                let entry = Entry::season(actor, Event::Deleted, series, season);
+                if let Err(e) = self.audit.record(&entry) {
+                    error!("{}", e);
+                }
+                Ok(Deletion::Complete)
+            }
+            SeasonDeletion::Failed(e) => Err(anyhow!(
+                "Deleting files of {} S{:02}: no files deleted: {}",
+                series.title,
+                season.season_number,
+                e
+            )),
+            SeasonDeletion::Partial {
+                deleted,
+                remaining,
+                error,
+            } => {
+                let paths = |ids: &[u32]| -> Vec<String> {
+                    files
+                        .iter()
+                        .filter(|f| ids.contains(&f.id))
+                        .map(|f| f.path.display().to_string())
+                        .collect()
+                };
+                let freed: u128 = files
+                    .iter()
+                    .filter(|f| deleted.contains(&f.id))
+                    .map(|f| f.size)
+                    .sum();
+                metrics::BYTES_FREED.inc_by(freed as u64);
+                let entry = Entry {
+                    size_bytes: freed,
+                    ..Entry::season(actor, Event::PartiallyDeleted, series, season)
+                };
+                if let Err(e) = self.audit.record(&entry) {
+                    error!("{}", e);
+                }
+                Ok(Deletion::Partial {
+                    freed,
+                    error: anyhow!(
+                        "Deleting files of {} S{:02}: deleted {:?}, but {:?} remain: {}",
+                        series.title,
+                        season.season_number,
+                        paths(&deleted),
+                        paths(&remaining),
+                        error
+                    ),
+                })
+            }
+        }
+    }
+}
@@ -1,13 +1,25 @@
 //! Sonarr Plex Cleaner CLI Subcommands
 
+mod check;
+mod daemon;
+mod explain;
+mod init;
+mod serve;
+mod stats;
 mod tv;
+mod unmatched;
 mod version;
 
-use self::{tv::TVCommand, version::VersionCommand};
+use self::{
+    check::CheckCommand, daemon::DaemonCommand, explain::ExplainCommand, init::InitCommand,
+    serve::ServeCommand, stats::StatsCommand, tv::TVCommand, unmatched::UnmatchedCommand,
+    version::VersionCommand,
+};
 use crate::config::SonarrPlexCleanerCliConfig;
 use abscissa_core::config::Override;
 use abscissa_core::{Command, Configurable, FrameworkError, Help, Options, Runnable};
 use dirs::{config_dir, home_dir};
+use std::env;
 use std::path::PathBuf;
 
 /// Sonarr Plex Cleaner config file name.
@@ -24,25 +36,70 @@ pub enum SonarrPlexCleanerCliCommand {
     #[options(help = "clean up TV seasons in sonarr&plex")]
     Tv(TVCommand),
 
+    /// The `check` subcommand for validating the configuration
+    #[options(help = "check the configuration and the connections to all servers")]
+    Check(CheckCommand),
+
+    /// The `daemon` subcommand for cleaning up periodically
+    #[options(help = "keep running and clean up on a schedule")]
+    Daemon(DaemonCommand),
+
+    /// The `explain` subcommand for showing why seasons are kept
+    #[options(help = "explain why the seasons of a series are kept or deleted")]
+    Explain(ExplainCommand),
+
+    /// The `init` subcommand for creating the config file
+    #[options(help = "create the configuration file interactively")]
+    Init(InitCommand),
+
+    /// The `serve` subcommand for reviewing the plan in a browser
+    #[options(help = "serve a web UI for reviewing deletions")]
+    Serve(ServeCommand),
+
+    /// The `stats` subcommand for reporting on disk usage
+    #[options(help = "report where the disk space goes")]
+    Stats(StatsCommand),
+
+    /// The `unmatched` subcommand for finding shows that can't be cleaned up
+    #[options(help = "list shows and seasons that sonarr and the media server disagree on")]
+    Unmatched(UnmatchedCommand),
+
     /// The `version` subcommand
     #[options(help = "display version information")]
     Version(VersionCommand),
 }
 
+/// Environment variable that sets the location of the config file.
+pub const CONFIG_PATH_VAR: &str = "SPC_CONFIG";
+
+/// Returns the location of the config file: the path in `$SPC_CONFIG`,
+/// or else the file in the OS's appropriate `config_dir` (if unknown,
+/// the `home_dir`).
+pub fn default_config_path() -> PathBuf {
+    if let Some(path) = env::var_os(CONFIG_PATH_VAR) {
+        return PathBuf::from(path);
+    }
+    config_dir()
+        .or_else(home_dir)
+        .expect("user home and config dir are unknown")
+        .join(CONFIG_FILE)
+}
+
 /// The way we load the CLI file:
 ///
-/// The config file is mandatory, and we search for it in the OS's
-/// appropriate `config_dir` (if unknown, the `home_dir`).
+/// The config file is mandatory (unless all settings come from the
+/// environment), and we search for it in the OS's appropriate
+/// `config_dir` (if unknown, the `home_dir`). The global `-c/--config`
+/// option or `$SPC_CONFIG` point at a different file.
 impl Configurable<SonarrPlexCleanerCliConfig> for SonarrPlexCleanerCliCommand {
     /// Location of the configuration file
     fn config_path(&self) -> Option<PathBuf> {
-        // Tool must be run with a config file in place.
-        Some(PathBuf::from(
-            config_dir()
-                .or_else(home_dir)
-                .expect("user home and config dir are unknown")
-                .join(CONFIG_FILE),
-        ))
+        match self {
+            // `init` creates the config file, so it can't require one.
+            SonarrPlexCleanerCliCommand::Init(_) => None,
+            // Tool must be run with a config file in place.
+            _ => Some(default_config_path()),
+        }
     }
 
     /// Override config settings from the commandline.
@@ -0,0 +1,251 @@
+//! `check` subcommand - validates the configuration and checks that
+//! every server can be reached.
+
+use crate::config::{self, ServerSettings, SonarrPlexCleanerCliConfig, Viewer};
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::services::jellyfin::JellyfinClient;
+use crate::services::plex::{MediaKind, PlexClient};
+use crate::services::sonarr::SonarrClient;
+
+use abscissa_core::{Command, Options, Runnable};
+use futures::future::join;
+use reqwest::{StatusCode, Url};
+use std::error::Error;
+use std::process;
+
+/// `check` subcommand - checks the server URLs in the configuration,
+/// connects to Sonarr and the media server, makes sure that the API
+/// keys work and that the Jellyfin user and the retain tag exist, and
+/// prints the results along with hints on how to fix any problems.
+///
+/// Exits with status 1 if any check failed.
+#[derive(Command, Debug, Options, Default)]
+pub struct CheckCommand {}
+
+/// The result of a single check.
+struct Check {
+    name: String,
+    outcome: Result<String, Failure>,
+}
+
+/// What went wrong in a check, and how to fix it.
+struct Failure {
+    error: String,
+    hint: String,
+}
+
+impl Failure {
+    fn new(error: impl ToString, hint: impl ToString) -> Failure {
+        Failure {
+            error: error.to_string(),
+            hint: hint.to_string(),
+        }
+    }
+
+    /// Describes a failed request, with a hint that depends on
+    /// whether the server rejected the API key.
+    fn request(error: &(dyn Error + 'static), key_hint: &str, url_hint: &str) -> Failure {
+        let status = error
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status());
+        let hint = match status {
+            Some(StatusCode::UNAUTHORIZED) | Some(StatusCode::FORBIDDEN) => key_hint,
+            _ => url_hint,
+        };
+        Failure::new(error, hint)
+    }
+}
+
+fn check_url(name: &str, url: &Url, setting: &str) -> Check {
+    let outcome = if url.scheme() != "http" && url.scheme() != "https" {
+        Err(Failure::new(
+            format!("{} is not an http or https URL", url),
+            format!("Set {} to an http:// or https:// URL", setting),
+        ))
+    } else if url.host_str().is_none() {
+        Err(Failure::new(
+            format!("{} has no host name", url),
+            format!("Set {} to a URL with a host name", setting),
+        ))
+    } else {
+        Ok(url.to_string())
+    };
+    Check {
+        name: name.to_string(),
+        outcome,
+    }
+}
+
+async fn check_sonarr(config: &SonarrPlexCleanerCliConfig) -> Vec<Check> {
+    let mut checks = vec![];
+    checks.push(check_url("sonarr URL", &config.tv.url, "tv.url"));
+    let key_hint = "Check tv.api_key; Sonarr shows it in Settings -> General";
+    let url_hint = "Check that tv.url is the URL of Sonarr, e.g. https://sonarr.example.com/";
+
+    let sonarr = match SonarrClient::from_config(&config.tv) {
+        Ok(sonarr) => sonarr,
+        Err(e) => {
+            checks.push(Check {
+                name: "sonarr".to_string(),
+                outcome: Err(Failure::new(e, key_hint)),
+            });
+            return checks;
+        }
+    };
+    let status = match sonarr.system_status().await {
+        Ok(status) => sonarr
+            .api_version()
+            .await
+            .map(|api| format!("Sonarr {}, {}", status.version, api)),
+        Err(e) => Err(e),
+    };
+    let reachable = status.is_ok();
+    checks.push(Check {
+        name: "sonarr".to_string(),
+        outcome: status.map_err(|e| Failure::request(e.as_ref(), key_hint, url_hint)),
+    });
+    if !reachable {
+        return checks;
+    }
+
+    if let Some(name) = &config.retention.retain_tag {
+        let outcome = match sonarr.fetch_tags().await {
+            Ok(tags) => match tags.get(name) {
+                Some(_) => Ok(format!("tag {:?} exists", name)),
+                None => Err(Failure::new(
+                    format!("no tag named {:?}", name),
+                    format!(
+                        "Create the tag in Sonarr, or set retention.retain_tag to one of {:?}",
+                        tags.labels()
+                    ),
+                )),
+            },
+            Err(e) => Err(Failure::request(e.as_ref(), key_hint, url_hint)),
+        };
+        checks.push(Check {
+            name: "retain tag".to_string(),
+            outcome,
+        });
+    }
+    checks
+}
+
+async fn check_plex(conf: &ServerSettings<config::Plex>) -> Vec<Check> {
+    let mut checks = vec![];
+    checks.push(check_url("plex URL", &conf.url, "plex.url"));
+    let key_hint = "Check plex.api_key: \
+                    https://support.plex.tv/articles/204059436-finding-an-authentication-token-x-plex-token/";
+    let url_hint =
+        "Check that plex.url is the Plex server's URL, e.g. http://plex.example.com:32400/";
+
+    let plex = match PlexClient::from_config(conf) {
+        Ok(plex) => plex,
+        Err(e) => {
+            checks.push(Check {
+                name: "plex".to_string(),
+                outcome: Err(Failure::new(e, key_hint)),
+            });
+            return checks;
+        }
+    };
+    let identity = plex.identity().await;
+    let reachable = identity.is_ok();
+    checks.push(Check {
+        name: "plex".to_string(),
+        outcome: identity
+            .map(|identity| format!("Plex {}", identity.version))
+            .map_err(|e| Failure::request(e.as_ref(), key_hint, url_hint)),
+    });
+    if !reachable {
+        return checks;
+    }
+
+    let outcome = match plex.libraries().await {
+        Ok(libraries) => {
+            let tv = libraries.iter().filter(|l| l.kind == MediaKind::TV).count();
+            if tv == 0 {
+                Err(Failure::new(
+                    "no TV libraries",
+                    "Add a TV library in Plex, or check that the API key's user can see it",
+                ))
+            } else {
+                Ok(format!("{} TV libraries", tv))
+            }
+        }
+        Err(e) => Err(Failure::request(e.as_ref(), key_hint, url_hint)),
+    };
+    checks.push(Check {
+        name: "plex API key".to_string(),
+        outcome,
+    });
+    checks
+}
+
+async fn check_jellyfin(conf: &config::JellyfinSettings) -> Vec<Check> {
+    let mut checks = vec![];
+    checks.push(check_url(
+        "jellyfin URL",
+        &conf.server.url,
+        "jellyfin.server.url",
+    ));
+    let key_hint = "Check jellyfin.server.api_key; admins can create API keys in the dashboard";
+    let url_hint = "Check that jellyfin.server.url is the Jellyfin server's URL, \
+                    e.g. http://jellyfin.example.com:8096/";
+
+    let info = JellyfinClient::system_info(&conf.server).await;
+    let reachable = info.is_ok();
+    checks.push(Check {
+        name: "jellyfin".to_string(),
+        outcome: info
+            .map(|info| format!("{} {}", info.server_name, info.version))
+            .map_err(|e| Failure::request(e.as_ref(), key_hint, url_hint)),
+    });
+    if !reachable {
+        return checks;
+    }
+
+    let outcome = match JellyfinClient::user_names(&conf.server).await {
+        Ok(names) if names.contains(&conf.user) => Ok(format!("user {:?} exists", conf.user)),
+        Ok(names) => Err(Failure::new(
+            format!("no user named {:?}", conf.user),
+            format!("Set jellyfin.user to one of {:?}", names),
+        )),
+        Err(e) => Err(Failure::request(e.as_ref(), key_hint, url_hint)),
+    };
+    checks.push(Check {
+        name: "jellyfin user".to_string(),
+        outcome,
+    });
+    checks
+}
+
+impl Runnable for CheckCommand {
+    /// Run the checks.
+    fn run(&self) {
+        let config = app_config();
+        let viewer = async {
+            match &config.viewer {
+                Viewer::Plex(plex) => check_plex(plex).await,
+                Viewer::Jellyfin(jellyfin) => check_jellyfin(jellyfin).await,
+            }
+        };
+        let (mut checks, viewer_checks) = block_on(join(check_sonarr(&config), viewer));
+        checks.extend(viewer_checks);
+
+        let mut failed = false;
+        for check in checks.iter() {
+            match &check.outcome {
+                Ok(detail) => println!("{:<14} ok    {}", check.name, detail),
+                Err(failure) => {
+                    failed = true;
+                    println!("{:<14} FAIL  {}", check.name, failure.error);
+                    println!("{:<14}       hint: {}", "", failure.hint);
+                }
+            }
+        }
+        if failed {
+            process::exit(1);
+        }
+    }
+}
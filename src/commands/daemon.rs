@@ -0,0 +1,140 @@
+//! `daemon` subcommand - keeps running and cleans up on a schedule.
+
+use crate::cleaner::{Action, Cleaner, RunInProgress, RunSummary};
+use crate::config::DaemonSettings;
+use crate::metrics;
+use crate::notify::Notifier;
+use crate::prelude::*;
+use crate::runtime::block_on;
+
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// `daemon` subcommand - stays running, keeps the API clients
+/// around and runs the cleanups on the schedule configured in the
+/// `[daemon]` section.
+#[derive(Command, Debug, Options, Default)]
+pub struct DaemonCommand {
+    /// Whether to actually delete files.
+    #[options(short = "f")]
+    delete_files: bool,
+}
+
+/// When the daemon runs.
+enum Schedule {
+    /// At the times matching a cron expression.
+    Cron(Box<cron::Schedule>),
+
+    /// At a fixed interval.
+    Every(Duration),
+}
+
+impl Schedule {
+    fn from_config(conf: &DaemonSettings) -> Result<Schedule> {
+        match &conf.schedule {
+            Some(expr) => Ok(Schedule::Cron(Box::new(
+                cron::Schedule::from_str(expr)
+                    .map_err(|e| anyhow!("Invalid schedule {:?}: {}", expr, e))?,
+            ))),
+            None if conf.interval == Duration::from_secs(0) => {
+                Err(anyhow!("The daemon interval must not be zero"))
+            }
+            None => Ok(Schedule::Every(conf.interval)),
+        }
+    }
+
+    /// Returns the next time a run should start.
+    fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Cron(schedule) => schedule.after(&now).next(),
+            Schedule::Every(interval) => chrono::Duration::from_std(*interval)
+                .ok()
+                .map(|interval| now + interval),
+        }
+    }
+}
+
+/// Sleeps until `deadline`. Returns false if `shutdown` was set in
+/// the meantime.
+fn sleep_until(deadline: DateTime<Utc>, shutdown: &AtomicBool) -> bool {
+    while !shutdown.load(Ordering::SeqCst) {
+        let remaining = match (deadline - Utc::now()).to_std() {
+            Ok(remaining) => remaining,
+            Err(_) => return true, // deadline has passed
+        };
+        thread::sleep(remaining.min(Duration::from_secs(1)));
+    }
+    false
+}
+
+impl Runnable for DaemonCommand {
+    /// Start the daemon.
+    fn run(&self) {
+        let config = app_config();
+        let schedule = Schedule::from_config(&config.daemon).expect("Invalid daemon schedule");
+        let cleaner =
+            block_on(Cleaner::from_config(&config)).expect("Could not set up API clients");
+        let notifier =
+            Notifier::from_config(&config.notifications).expect("Could not set up notifications");
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        for signal in &[signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+            signal_hook::flag::register(*signal, Arc::clone(&shutdown))
+                .expect("Could not install signal handler");
+        }
+
+        if let Some(addr) = config.daemon.metrics_listen {
+            thread::spawn(move || {
+                if let Err(e) = metrics::serve(addr) {
+                    error!("Could not serve metrics on {}: {}", addr, e);
+                }
+            });
+        }
+
+        loop {
+            let max_jitter = config.daemon.jitter.as_millis() as u64;
+            let jitter =
+                chrono::Duration::milliseconds(rand::thread_rng().gen_range(0..=max_jitter) as i64);
+            let next = match schedule.next_after(Utc::now()) {
+                Some(next) => next + jitter,
+                None => {
+                    info!("No more runs scheduled");
+                    break;
+                }
+            };
+            info!("Next cleanup run at {}", next);
+            if !sleep_until(next, &shutdown) {
+                break;
+            }
+
+            let result = block_on(cleaner.run("daemon", !self.delete_files, |_, _| {
+                if shutdown.load(Ordering::SeqCst) {
+                    Action::Stop
+                } else if self.delete_files {
+                    Action::Delete
+                } else {
+                    Action::DryRun
+                }
+            }));
+            match result {
+                Ok(summary) => notifier.send(&summary),
+                Err(e) if e.is::<RunInProgress>() => warn!("Skipping run: {}", e),
+                Err(e) => {
+                    error!("Cleanup run failed: {}", e);
+                    notifier.send(&RunSummary {
+                        errors: vec![e.to_string()],
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        info!("Shutting down");
+    }
+}
@@ -0,0 +1,208 @@
+//! `explain` subcommand - shows why the seasons of a series are kept
+//! or deleted.
+
+use crate::cleaner::Cleaner;
+use crate::plan::{self, format_age, format_size, season_key, Decision, Verdict};
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::services::sonarr::Series;
+
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use futures::future::join;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::error::Error;
+use std::process;
+
+/// `explain` subcommand - prints the decision trail for every season
+/// of a single series: whether the media server knows the show, how
+/// much of each season was watched, whether it is still airing, how
+/// old it is compared to the retention period, and how much space it
+/// takes up.
+#[derive(Command, Debug, Options, Default)]
+pub struct ExplainCommand {
+    /// Title or Sonarr ID of the series. A number that isn't the ID
+    /// of a series is looked up as a title.
+    #[options(free)]
+    series: Vec<String>,
+}
+
+/// Number of similarly-titled shows to suggest if there is no match.
+const SUGGESTIONS: usize = 5;
+
+/// True if `e` is Sonarr answering with a 404.
+fn is_not_found(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        == Some(StatusCode::NOT_FOUND)
+}
+
+/// Looks up a series by its Sonarr ID, or by its title. Titles can be
+/// numbers too (e.g. "1883"), so a number that isn't an ID is looked
+/// up as a title.
+async fn find_series(cleaner: &Cleaner, query: &str) -> Result<Series> {
+    if let Ok(id) = query.parse::<u32>() {
+        match cleaner.sonarr.fetch_series(id).await {
+            Ok(series) => return Ok(series),
+            Err(e) if is_not_found(&*e) => {}
+            Err(e) => return Err(anyhow!("sonarr: fetching series {}: {}", id, e)),
+        }
+    }
+    let serieses = cleaner.all_series().await?;
+    let position = serieses.iter().position(|s| s.title == query).or_else(|| {
+        serieses
+            .iter()
+            .position(|s| s.title.to_lowercase() == query.to_lowercase())
+    });
+    match position {
+        Some(i) => Ok(serieses.into_iter().nth(i).unwrap()),
+        None => Err(anyhow!(
+            "No series titled {:?} in sonarr. Similar titles: {:?}",
+            query,
+            plan::similar_titles(
+                query,
+                serieses.iter().map(|s| s.title.as_str()),
+                SUGGESTIONS
+            )
+        )),
+    }
+}
+
+fn print_season(decision: &Decision<'_>, cleaner: &Cleaner, service: &str) {
+    let season = decision.season;
+    let stats = &season.statistics;
+    let now = Utc::now();
+    println!();
+    match &decision.verdict {
+        Verdict::Delete => println!("Season {}: delete", season.season_number),
+        Verdict::Keep(reason) => {
+            println!("Season {}: keep, because {}", season.season_number, reason)
+        }
+    }
+
+    match decision.watch_state {
+        Some(state) => println!(
+            "  watched:    {}/{} episodes on {}",
+            state.viewed_episodes, state.episodes, service
+        ),
+        None => println!(
+            "  watched:    no {:?} on {}",
+            season_key(decision.series, season).1,
+            service
+        ),
+    }
+
+    match (stats.next_airing, stats.previous_airing) {
+        (Some(next), _) => println!(
+            "  airing:     still airing, next episode on {}",
+            next.format("%Y-%m-%d")
+        ),
+        (None, Some(previous)) => println!(
+            "  airing:     finished, last episode aired on {}",
+            previous.format("%Y-%m-%d")
+        ),
+        (None, None) => println!("  airing:     nothing aired yet"),
+    }
+
+    if let Some(previous) = stats.previous_airing {
+        let age = now - previous;
+        let retain_duration = cleaner.policy.retain_duration;
+        if age > retain_duration {
+            println!(
+                "  age:        {}, older than retain_duration {}",
+                format_age(age),
+                format_age(retain_duration)
+            );
+        } else {
+            println!(
+                "  age:        {}, within retain_duration {}",
+                format_age(age),
+                format_age(retain_duration)
+            );
+        }
+    }
+    if let Some(deletable_at) = decision.deletable_at(&cleaner.policy) {
+        println!("  deletable:  from {}", deletable_at.format("%Y-%m-%d"));
+    }
+
+    println!(
+        "  size:       {} in {} of {} episodes",
+        format_size(stats.size_on_disk),
+        stats.episode_file_count,
+        stats.total_episode_count
+    );
+}
+
+impl Runnable for ExplainCommand {
+    /// Explain the decisions for a series.
+    fn run(&self) {
+        let query = self.series.join(" ");
+        if query.is_empty() {
+            eprintln!("explain: name a series by its title or Sonarr ID");
+            process::exit(2);
+        }
+        let config = app_config();
+        let cleaner =
+            block_on(Cleaner::from_config(&config)).expect("Could not set up API clients");
+        let service = cleaner.viewer.service_name();
+
+        let series = match block_on(find_series(&cleaner, &query)) {
+            Ok(series) => series,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        let (tags, shows) = block_on(join(cleaner.sonarr.fetch_tags(), cleaner.viewer.shows()));
+        let tags = tags.expect("Fetching sonarr tags");
+        let shows = shows.expect("Fetching shows");
+        let mut watched = HashMap::new();
+        let matches: Vec<&str> = shows
+            .iter()
+            .filter(|(title, _)| *title == series.title)
+            .map(|(_, id)| id.as_str())
+            .collect();
+        for id in matches.iter() {
+            watched.extend(
+                block_on(cleaner.viewer.series_watch_states(id)).expect("Fetching watch states"),
+            );
+        }
+
+        println!("{} (sonarr ID {})", series.title, series.id);
+        let labels: Vec<&str> = series
+            .tags
+            .iter()
+            .map(|id| tags.label(*id).unwrap_or("?"))
+            .collect();
+        println!("  tags:       {:?}", labels);
+        match &cleaner.policy.retain_tag {
+            Some((name, id)) if series.tags.contains(id) => {
+                println!("  retain tag: {:?} is set, nothing gets deleted", name)
+            }
+            Some((name, _)) => println!("  retain tag: {:?} is not set", name),
+            None => println!("  retain tag: none configured"),
+        }
+        let label = format!("{}:", service);
+        if !matches.is_empty() {
+            println!("  {:<12}matched show {:?}", label, series.title);
+        } else {
+            println!(
+                "  {:<12}no show titled {:?}. Similar titles: {:?}",
+                label,
+                series.title,
+                plan::similar_titles(
+                    &series.title,
+                    shows.iter().map(|(title, _)| title.as_str()),
+                    SUGGESTIONS
+                )
+            );
+        }
+
+        let serieses = [series];
+        for decision in plan::evaluate(&cleaner.policy, &serieses, &watched, Utc::now()).iter() {
+            print_season(decision, &cleaner, service);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! `init` subcommand - asks for the server settings and writes the
+//! configuration file.
+
+use crate::config::{self, JellyfinSettings, ServerSettings, SonarrPlexCleanerCliConfig};
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::services::jellyfin::JellyfinClient;
+use crate::services::plex::PlexClient;
+use crate::services::sonarr::SonarrClient;
+
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+
+/// `init` subcommand - interactively creates the configuration file:
+/// asks for the Sonarr and media server URLs and API keys, connects
+/// to each server to make sure they work, offers the Jellyfin users
+/// and Sonarr tags to choose from, and writes the result to the
+/// location that the other subcommands read it from (the file given
+/// with the global `-c` option, if any).
+#[derive(Command, Debug, Options, Default)]
+pub struct InitCommand {
+    /// Overwrite an existing config file without asking.
+    #[options(no_short)]
+    force: bool,
+}
+
+/// The config file, as written by `init`.
+#[derive(Serialize)]
+struct ConfigFile {
+    tv: Server,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plex: Option<Server>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jellyfin: Option<Jellyfin>,
+    retention: Retention,
+}
+
+#[derive(Serialize)]
+struct Server {
+    url: String,
+    api_key: String,
+}
+
+#[derive(Serialize)]
+struct Jellyfin {
+    user: String,
+    server: Server,
+}
+
+#[derive(Serialize)]
+struct Retention {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retain_tag: Option<String>,
+    retain_duration: String,
+}
+
+/// Asks `question` and returns the answer, or `default` if the
+/// answer is empty.
+fn ask(question: &str, default: Option<&str>) -> String {
+    let stdin = io::stdin();
+    loop {
+        match default {
+            Some(default) if !default.is_empty() => print!("{} [{}]: ", question, default),
+            _ => print!("{}: ", question),
+        }
+        io::stdout().flush().expect("flushing stdout");
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).expect("reading stdin") == 0 {
+            println!();
+            process::exit(1);
+        }
+        match (line.trim(), default) {
+            ("", Some(default)) => return default.to_string(),
+            ("", None) => continue,
+            (answer, _) => return answer.to_string(),
+        }
+    }
+}
+
+/// Asks a yes/no question.
+fn confirm(question: &str, default: bool) -> bool {
+    let answer = ask(question, Some(if default { "y" } else { "n" }));
+    answer.starts_with('y') || answer.starts_with('Y')
+}
+
+/// Asks for a URL, adding a trailing slash to make it clear that API
+/// paths go below it.
+fn ask_url(question: &str, default: &str) -> Url {
+    loop {
+        let mut answer = ask(question, Some(default));
+        if !answer.ends_with('/') {
+            answer.push('/');
+        }
+        match Url::parse(&answer) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => return url,
+            Ok(_) => println!("Please enter an http:// or https:// URL."),
+            Err(e) => println!("That's not a valid URL: {}", e),
+        }
+    }
+}
+
+/// Asks for a server URL and API key until `connect` succeeds with
+/// them, or the operator gives up.
+fn ask_server<T, C, F>(name: &str, default_url: &str, connect: F) -> (ServerSettings<T>, C)
+where
+    F: Fn(&ServerSettings<T>) -> Result<C>,
+{
+    loop {
+        let url = ask_url(&format!("{} URL", name), default_url);
+        let api_key = ask(&format!("{} API key", name), None);
+        let settings = ServerSettings::new(url, api_key);
+        match connect(&settings) {
+            Ok(client) => return (settings, client),
+            Err(e) => {
+                println!("Could not connect to {}: {}", name, e);
+                if !confirm("Try again?", true) {
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Lets the operator pick one of `choices`, or enter something else.
+fn choose(question: &str, choices: &[String], default: Option<&str>) -> String {
+    for (i, choice) in choices.iter().enumerate() {
+        println!("  {}) {}", i + 1, choice);
+    }
+    let answer = ask(question, default);
+    match answer.parse::<usize>() {
+        Ok(n) if n >= 1 && n <= choices.len() => choices[n - 1].clone(),
+        _ => answer,
+    }
+}
+
+fn server<T>(settings: &ServerSettings<T>) -> Server {
+    Server {
+        url: settings.url.to_string(),
+        api_key: settings
+            .api_key
+            .resolve()
+            .expect("init only asks for inline API keys")
+            .expose_secret()
+            .as_str()
+            .to_string(),
+    }
+}
+
+fn write_config(path: &Path, contents: &str) -> Result<()> {
+    // make sure that the other subcommands will be able to read it:
+    toml::from_str::<SonarrPlexCleanerCliConfig>(contents)
+        .map_err(|e| anyhow!("generated an invalid config: {}", e))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, contents)?;
+    #[cfg(unix)]
+    {
+        // the file contains API keys:
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+impl Runnable for InitCommand {
+    /// Run the wizard.
+    fn run(&self) {
+        let path = app_reader().config_path();
+        if path.exists()
+            && !self.force
+            && !confirm(&format!("{} exists. Overwrite it?", path.display()), false)
+        {
+            process::exit(1);
+        }
+
+        println!("Sonarr: use the URL you reach Sonarr at.");
+        let (tv, sonarr) =
+            ask_server::<config::Sonarr, _, _>("Sonarr", "http://localhost:8989/", |settings| {
+                let sonarr = SonarrClient::from_config(settings).map_err(|e| anyhow!("{}", e))?;
+                let status = block_on(sonarr.system_status()).map_err(|e| anyhow!("{}", e))?;
+                println!("Connected to Sonarr {}.", status.version);
+                Ok(sonarr)
+            });
+
+        let viewer = choose(
+            "Media server",
+            &["plex".to_string(), "jellyfin".to_string()],
+            Some("plex"),
+        );
+        let (plex, jellyfin) = if viewer == "jellyfin" {
+            let (server_settings, users) = ask_server::<config::Jellyfin, _, _>(
+                "Jellyfin",
+                "http://localhost:8096/",
+                |settings| {
+                    let info = block_on(JellyfinClient::system_info(settings))?;
+                    println!("Connected to {} {}.", info.server_name, info.version);
+                    block_on(JellyfinClient::user_names(settings))
+                },
+            );
+            println!("Jellyfin users:");
+            let user = choose("User whose watched states count", &users, None);
+            let jellyfin = JellyfinSettings {
+                user,
+                server: server_settings,
+            };
+            if let Err(e) = block_on(JellyfinClient::from_config(&jellyfin)) {
+                println!("Warning: could not look up the user: {}", e);
+            }
+            (
+                None,
+                Some(Jellyfin {
+                    user: jellyfin.user.clone(),
+                    server: server(&jellyfin.server),
+                }),
+            )
+        } else {
+            println!("Plex: to find your API key, see");
+            println!("https://support.plex.tv/articles/204059436-finding-an-authentication-token-x-plex-token/");
+            let (settings, _) =
+                ask_server::<config::Plex, _, _>("Plex", "http://localhost:32400/", |settings| {
+                    let plex = PlexClient::from_config(settings).map_err(|e| anyhow!("{}", e))?;
+                    let identity = block_on(plex.identity()).map_err(|e| anyhow!("{}", e))?;
+                    block_on(plex.libraries()).map_err(|e| anyhow!("{}", e))?;
+                    println!("Connected to Plex {}.", identity.version);
+                    Ok(())
+                });
+            (Some(server(&settings)), None)
+        };
+
+        let tags = match block_on(sonarr.fetch_tags()) {
+            Ok(tags) => tags.labels().into_iter().map(String::from).collect(),
+            Err(e) => {
+                println!("Could not fetch the Sonarr tags: {}", e);
+                vec![]
+            }
+        };
+        println!("Shows tagged with the retain tag are never deleted. Sonarr tags:");
+        let retain_tag = choose("Retain tag (\"-\" for none)", &tags, Some("retain"));
+        if retain_tag != "-" && !tags.contains(&retain_tag) {
+            println!(
+                "Warning: Sonarr has no tag {:?} yet. Create it before running the cleaner.",
+                retain_tag
+            );
+        }
+        let retain_duration = loop {
+            let answer = ask(
+                "How long to keep watched seasons after they last aired",
+                Some("14d"),
+            );
+            match humantime::parse_duration(&answer) {
+                Ok(_) => break answer,
+                Err(e) => println!("That's not a duration like \"14d\": {}", e),
+            }
+        };
+
+        let file = ConfigFile {
+            tv: server(&tv),
+            plex,
+            jellyfin,
+            retention: Retention {
+                retain_tag: Some(retain_tag).filter(|tag| tag != "-"),
+                retain_duration,
+            },
+        };
+        let contents = toml::to_string(&file).expect("Formatting the config file");
+        write_config(&path, &contents).expect("Writing the config file");
+        println!("Wrote {}.", path.display());
+    }
+}
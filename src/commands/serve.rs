@@ -0,0 +1,31 @@
+//! `serve` subcommand - runs the web UI for reviewing the cleanup plan.
+
+use crate::cleaner::Cleaner;
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::web::WebServer;
+
+use abscissa_core::{Command, Options, Runnable};
+
+/// `serve` subcommand - serves a web UI and HTTP API that shows the
+/// seasons eligible for deletion, and lets users keep or delete them.
+#[derive(Command, Debug, Options, Default)]
+pub struct ServeCommand {
+    /// Whether to delete seasons that become eligible for deletion
+    /// through a "watched" webhook.
+    #[options(short = "f")]
+    delete_files: bool,
+}
+
+impl Runnable for ServeCommand {
+    /// Start the web server.
+    fn run(&self) {
+        let config = app_config();
+        let cleaner =
+            block_on(Cleaner::from_config(&config)).expect("Could not set up API clients");
+        WebServer::new(&cleaner, &config, self.delete_files)
+            .expect("Could not set up web server")
+            .serve()
+            .expect("Serving the web UI");
+    }
+}
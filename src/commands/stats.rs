@@ -0,0 +1,113 @@
+//! `stats` subcommand - reports where the disk space goes.
+
+use crate::cleaner::Cleaner;
+use crate::plan::format_size;
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::stats::{LibraryStats, OutputFormat};
+
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::Result;
+use chrono::Utc;
+use futures::future::join;
+use serde_json::json;
+use std::io;
+
+/// `stats` subcommand - aggregates the size of every series in Sonarr
+/// and the watch states on the media server into a report of how much
+/// space is taken up by watched and unwatched seasons, the largest
+/// series, the series nobody watched, and how much space the
+/// retention policy would free up.
+#[derive(Command, Debug, Options, Default)]
+pub struct StatsCommand {
+    /// Output format: table, json or csv (one row per series).
+    #[options(no_short, default = "table")]
+    format: OutputFormat,
+
+    /// Number of largest series to list.
+    #[options(no_short, default = "10")]
+    top: usize,
+}
+
+fn print_table(stats: &LibraryStats, top: usize) {
+    println!(
+        "Total:        {} in {} episode files",
+        format_size(stats.size_bytes),
+        stats.episode_files
+    );
+    println!("Watched:      {}", format_size(stats.watched_bytes));
+    println!("Unwatched:    {}", format_size(stats.unwatched_bytes));
+    println!(
+        "Reclaimable:  {} (under the current retention policy)",
+        format_size(stats.reclaimable_bytes)
+    );
+
+    println!();
+    println!("Largest series:");
+    println!(
+        "  {:>12}  {:>12}  {:>12}  TITLE",
+        "SIZE", "WATCHED", "RECLAIMABLE"
+    );
+    for series in stats.series.iter().take(top) {
+        println!(
+            "  {:>12}  {:>12}  {:>12}  {}",
+            format_size(series.size_bytes),
+            format_size(series.watched_bytes),
+            format_size(series.reclaimable_bytes),
+            series.title
+        );
+    }
+
+    println!();
+    println!("Never watched:");
+    for series in stats.never_watched() {
+        println!("  {:>12}  {}", format_size(series.size_bytes), series.title);
+    }
+}
+
+fn print_json(stats: &LibraryStats, top: usize) -> Result<()> {
+    let largest: Vec<_> = stats.series.iter().take(top).collect();
+    let never_watched: Vec<_> = stats.never_watched().collect();
+    let report = json!({
+        "episode_files": stats.episode_files,
+        "size_bytes": stats.size_bytes,
+        "watched_bytes": stats.watched_bytes,
+        "unwatched_bytes": stats.unwatched_bytes,
+        "reclaimable_bytes": stats.reclaimable_bytes,
+        "largest": largest,
+        "never_watched": never_watched,
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+fn print_csv(stats: &LibraryStats) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(io::stdout());
+    for series in stats.series.iter() {
+        writer.serialize(series)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+impl Runnable for StatsCommand {
+    /// Print the library statistics.
+    fn run(&self) {
+        let config = app_config();
+        let cleaner =
+            block_on(Cleaner::from_config(&config)).expect("Could not set up API clients");
+        let (watched, serieses) = block_on(join(
+            cleaner.viewer.all_watch_states(),
+            cleaner.all_series(),
+        ));
+        let watched = watched.expect("Fetching watch states");
+        let serieses = serieses.expect("Fetching serieses");
+        let stats = LibraryStats::collect(&cleaner.policy, &serieses, &watched, Utc::now());
+
+        match self.format {
+            OutputFormat::Table => print_table(&stats, self.top),
+            OutputFormat::Json => print_json(&stats, self.top).expect("Printing JSON"),
+            OutputFormat::Csv => print_csv(&stats).expect("Printing CSV"),
+        }
+    }
+}
@@ -1,20 +1,20 @@
 //! `tv` subcommand - cleans out entirely-watched TV seasons.
 
+use crate::cleaner::{Action, Cleaner, RunInProgress};
 use crate::config::SonarrPlexCleanerCliConfig;
-use crate::config::Viewer;
+use crate::metrics;
+use crate::notify::Notifier;
+use crate::plan::{format_size, Decision};
 use crate::prelude::*;
-use crate::services::jellyfin;
+use crate::runtime::block_on;
 
 use abscissa_core::config::Override;
 use abscissa_core::FrameworkError;
 
-use byte_unit::{Byte, ByteUnit};
-use chrono::Utc;
-use humantime::{format_duration, Duration};
-use std::collections::HashMap;
-use std::collections::HashSet;
-
-use crate::services::{plex, sonarr};
+use humantime::Duration;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process;
 
 use abscissa_core::{
     // config,
@@ -33,11 +33,21 @@ pub struct TVCommand {
     #[options(short = "f")]
     delete_files: bool,
 
+    /// Ask before deleting each season, and delete only the ones
+    /// that were approved.
+    #[options(short = "i")]
+    interactive: bool,
+
     /// How long we should retain a fully-watched season after airing.
     ///
     /// If unset, does not retain anything.
     #[options(no_short)]
     retain_for: Option<Duration>,
+
+    /// Write Prometheus metrics about the run to this file, for
+    /// node_exporter's textfile collector.
+    #[options(no_short)]
+    metrics_file: Option<PathBuf>,
 }
 
 impl Override<SonarrPlexCleanerCliConfig> for TVCommand {
@@ -53,157 +63,89 @@ impl Override<SonarrPlexCleanerCliConfig> for TVCommand {
     }
 }
 
+/// Shows a season that is eligible for deletion and asks the operator
+/// what to do with it.
+fn ask(decision: &Decision<'_>, file_count: usize, service: &str, can_retain: bool) -> Action {
+    let series = decision.series;
+    let season = decision.season;
+    println!();
+    println!("{} S{:02}", series.title, season.season_number);
+    println!(
+        "  size:     {} in {} files",
+        format_size(season.statistics.size_on_disk),
+        file_count
+    );
+    if let Some(aired) = season.statistics.previous_airing {
+        println!("  aired:    {}", aired.format("%Y-%m-%d"));
+    }
+    if let Some(state) = decision.watch_state {
+        println!(
+            "  watched:  {}/{} episodes on {}",
+            state.viewed_episodes, state.episodes, service
+        );
+    }
+
+    let prompt = if can_retain {
+        "Delete? [y]es, [n]o, [r]etain series, [q]uit: "
+    } else {
+        "Delete? [y]es, [n]o, [q]uit: "
+    };
+    let stdin = io::stdin();
+    loop {
+        print!("{}", prompt);
+        io::stdout().flush().expect("flushing stdout");
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).expect("reading stdin") == 0 {
+            return Action::Stop;
+        }
+        match line.trim() {
+            "y" | "yes" => return Action::Delete,
+            "n" | "no" => return Action::Skip,
+            "r" | "retain" if can_retain => return Action::Retain,
+            "q" | "quit" => return Action::Stop,
+            _ => continue,
+        }
+    }
+}
+
 impl Runnable for TVCommand {
     /// Start the application.
     fn run(&self) {
         let config = app_config();
-
-        let sonarr =
-            sonarr::SonarrClient::from_config(&config.tv).expect("Could not set up sonarr client");
-        let retain_tag = config.retention.retain_tag.as_ref().map(|tag_name| {
-            let tags = sonarr.fetch_tags().expect("sonarr tags");
-            let tag = tags
-                .get(&tag_name)
-                .expect(&format!("Tag {:?} not found in {:?}", &tag_name, tags));
-            (tag.label.to_string(), tag.id)
-        });
-        let retain_duration = chrono::Duration::from_std(config.retention.retain_duration)
-            .expect("Weird retain duration (past max chrono duration?)");
-        let watched_seasons: HashSet<(String, String)> = match &config.viewer {
-            Viewer::Plex(plex) => {
-                let plex =
-                    plex::PlexClient::from_config(&plex).expect("Could not set up plex client");
-                plex.all_tv_seasons()
-                    .expect("plex season listing")
-                    .into_iter()
-                    .filter(|s| s.fully_watched())
-                    .map(|s| (s.show_name, s.title))
-                    .collect()
+        let cleaner =
+            block_on(Cleaner::from_config(&config)).expect("Could not set up API clients");
+        let service = cleaner.viewer.service_name();
+        let can_retain = cleaner.policy.retain_tag.is_some();
+
+        let notifier =
+            Notifier::from_config(&config.notifications).expect("Could not set up notifications");
+
+        let dry_run = !self.interactive && !self.delete_files;
+        let result = block_on(cleaner.run("tv", dry_run, |decision, files| {
+            if self.interactive {
+                ask(decision, files.len(), service, can_retain)
+            } else if self.delete_files {
+                Action::Delete
+            } else {
+                Action::DryRun
             }
-            Viewer::Jellyfin(conf) => {
-                let jf = jellyfin::JellyfinClient::from_config(conf)
-                    .expect("Could not set up jellyfin/emby client");
-                jf.all_tv_seasons()
-                    .expect("Listing seasons")
-                    .into_iter()
-                    .filter(|s| s.fully_watched())
-                    .map(|s| (s.series_name, s.name))
-                    .collect()
+        }));
+        let summary = match result {
+            Ok(summary) => summary,
+            Err(e) if e.is::<RunInProgress>() => {
+                error!("Not cleaning up: {}", e);
+                process::exit(1);
             }
+            Err(e) => panic!("Cleaning up TV seasons: {}", e),
         };
-
-        let serieses = sonarr
-            .fetch_all_series()
-            .expect("sonarr: fetching serieses");
-
-        let to_delete: HashMap<&sonarr::Series, Vec<&sonarr::Season>> = serieses
-            .iter()
-            .filter_map(|series| {
-                if let Some((name, id)) = &retain_tag {
-                    if series.tags.contains(&id) {
-                        debug!("Skipping {} because it is tagged {:?}", series.title, name);
-                        return None;
-                    }
-                }
-
-                let seasons: Vec<&sonarr::Season> = series
-                    .seasons
-                    .iter()
-                    .filter_map(|season| {
-                        let still_airing = season.statistics.next_airing.is_some();
-                        let old_enough = season
-                            .statistics
-                            .previous_airing
-                            .map(|air| air + retain_duration < Utc::now())
-                            .unwrap_or(false);
-
-                        let is_watched = watched_seasons
-                            .get(&(
-                                series.title.clone(),
-                                format!("Season {}", season.season_number),
-                            ))
-                            .is_none();
-                        if is_watched {
-                            debug!(
-                                "Skipping {} - Season {:?} because it is unwatched",
-                                series.title, season.season_number
-                            );
-                            return None;
-                        }
-
-                        if still_airing {
-                            // season isn't done airing yet / isn't old enough:
-                            if season.statistics.previous_airing.is_some() {
-                                info!(
-                                    "Skipping {} - Season {:?} because it is still airing",
-                                    series.title, season.season_number
-                                );
-                            }
-                            return None;
-                        }
-                        if !old_enough {
-                            if let Some(air) = season.statistics.previous_airing {
-                                info!(
-                                    "Skipping {} - Season {:?} because age:{} < desired:{}",
-                                    series.title,
-                                    season.season_number,
-                                    format_duration(
-                                        (Utc::now() - air).to_std().expect("duration out of range")
-                                    ),
-                                    format_duration(
-                                        retain_duration.to_std().expect("duration out of range")
-                                    ),
-                                );
-                            }
-                            return None;
-                        }
-
-                        if season.statistics.size_on_disk == 0 {
-                            return None;
-                        }
-                        Some(season)
-                    })
-                    .collect();
-                if seasons.is_empty() {
-                    None
-                } else {
-                    Some((series, seasons))
-                }
-            })
-            .collect();
-
-        for (series, seasons) in to_delete.iter() {
-            let series_files = sonarr
-                .fetch_episode_files(series.id)
-                .expect(&format!("fetching files for {}", series.title));
-
-            for season in seasons {
-                let season_files: Vec<&sonarr::EpisodeFile> = series_files
-                    .iter()
-                    .filter(|f| f.season_number == season.season_number)
-                    .collect();
-                info!(
-                    "delete {} files: {} S{:02}: {}",
-                    season_files.len(),
-                    series.title,
-                    season.season_number,
-                    Byte::from_bytes(season.statistics.size_on_disk.into())
-                        .get_adjusted_unit(ByteUnit::GiB),
-                );
-                if self.delete_files {
-                    sonarr
-                        .unmonitor_season(series.id, season.season_number)
-                        .expect(&format!(
-                            "Unmonitoring season {} S{:02}",
-                            series.title, season.season_number
-                        ));
-                    for file in season_files.iter() {
-                        sonarr
-                            .delete_episode_file(file)
-                            .expect(&format!("deleting file {:?}", file));
-                    }
-                }
+        notifier.send(&summary);
+        if let Some(path) = &self.metrics_file {
+            if let Err(e) = metrics::write_textfile(path) {
+                error!("Could not write metrics to {:?}: {}", path, e);
             }
         }
+        if !summary.errors.is_empty() {
+            process::exit(1);
+        }
     }
 }
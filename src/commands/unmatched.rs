@@ -0,0 +1,356 @@
+//! `unmatched` subcommand - finds shows and seasons that Sonarr and
+//! the media server disagree about.
+
+use crate::cleaner::Cleaner;
+use crate::plan::{self, season_key};
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::services::sonarr::Series;
+use crate::services::viewer::{SeasonKey, WatchState};
+use crate::stats::OutputFormat;
+
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::Result;
+use futures::future::join;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::process;
+
+/// `unmatched` subcommand - lists the Sonarr series that the media
+/// server has no show for, the shows on the media server that Sonarr
+/// doesn't know, and the seasons that only one of them has. Such
+/// seasons never get cleaned up, as their watch state is unknown.
+///
+/// Exits with status 1 if there is anything to report.
+#[derive(Command, Debug, Options, Default)]
+pub struct UnmatchedCommand {
+    /// Output format: table, json or csv.
+    #[options(no_short, default = "table")]
+    format: OutputFormat,
+}
+
+/// Number of similarly-titled shows to suggest for a mismatch.
+const SUGGESTIONS: usize = 3;
+
+/// What is wrong with a show or season.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Problem {
+    /// A Sonarr series with files has no show on the media server.
+    NoShowOnMediaServer,
+
+    /// A show on the media server has no Sonarr series.
+    NoSeriesInSonarr,
+
+    /// A Sonarr season with files is missing on the media server.
+    SeasonMissingOnMediaServer,
+
+    /// A season on the media server is missing in Sonarr.
+    SeasonMissingInSonarr,
+}
+
+impl Problem {
+    fn heading(self, service: &str) -> String {
+        match self {
+            Problem::NoShowOnMediaServer => format!("Sonarr series that {} doesn't have", service),
+            Problem::NoSeriesInSonarr => format!("Shows on {} that Sonarr doesn't have", service),
+            Problem::SeasonMissingOnMediaServer => {
+                format!("Sonarr seasons that {} doesn't have", service)
+            }
+            Problem::SeasonMissingInSonarr => {
+                format!("Seasons on {} that Sonarr doesn't have", service)
+            }
+        }
+    }
+}
+
+/// A show or season that only one side knows about.
+#[derive(Debug, Clone, Serialize)]
+struct Mismatch {
+    problem: Problem,
+    title: String,
+    season: Option<String>,
+    similar: String,
+}
+
+fn has_files(series: &Series) -> bool {
+    series.seasons.iter().any(|s| s.statistics.size_on_disk > 0)
+}
+
+/// Compares the series in Sonarr against the seasons on the media
+/// server, matching them up the same way as the `tv` command does.
+fn find_mismatches(serieses: &[Series], watched: &HashMap<SeasonKey, WatchState>) -> Vec<Mismatch> {
+    let mut shows: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (show, season) in watched.keys() {
+        shows
+            .entry(show.as_str())
+            .or_default()
+            .insert(season.as_str());
+    }
+    let titles: BTreeSet<&str> = serieses.iter().map(|s| s.title.as_str()).collect();
+
+    let mut mismatches = vec![];
+    for series in serieses.iter().filter(|s| has_files(s)) {
+        if !shows.contains_key(series.title.as_str()) {
+            mismatches.push(Mismatch {
+                problem: Problem::NoShowOnMediaServer,
+                title: series.title.clone(),
+                season: None,
+                similar: plan::similar_titles(&series.title, shows.keys().cloned(), SUGGESTIONS)
+                    .join("; "),
+            });
+            continue;
+        }
+        for season in series
+            .seasons
+            .iter()
+            .filter(|s| s.statistics.size_on_disk > 0)
+        {
+            let key = season_key(series, season);
+            if !watched.contains_key(&key) {
+                mismatches.push(Mismatch {
+                    problem: Problem::SeasonMissingOnMediaServer,
+                    title: key.0,
+                    season: Some(key.1),
+                    similar: String::new(),
+                });
+            }
+        }
+    }
+
+    for (show, seasons) in shows.iter() {
+        let series = match serieses.iter().find(|s| s.title == *show) {
+            Some(series) => series,
+            None => {
+                mismatches.push(Mismatch {
+                    problem: Problem::NoSeriesInSonarr,
+                    title: show.to_string(),
+                    season: None,
+                    similar: plan::similar_titles(show, titles.iter().cloned(), SUGGESTIONS)
+                        .join("; "),
+                });
+                continue;
+            }
+        };
+        let sonarr_seasons: BTreeSet<String> = series
+            .seasons
+            .iter()
+            .map(|s| season_key(series, s).1)
+            .collect();
+        for season in seasons.iter().filter(|s| !sonarr_seasons.contains(**s)) {
+            mismatches.push(Mismatch {
+                problem: Problem::SeasonMissingInSonarr,
+                title: show.to_string(),
+                season: Some(season.to_string()),
+                similar: String::new(),
+            });
+        }
+    }
+    mismatches.sort_by(|a, b| (a.problem, &a.title).cmp(&(b.problem, &b.title)));
+    mismatches
+}
+
+fn print_table(mismatches: &[Mismatch], service: &str) {
+    let mut problem = None;
+    for mismatch in mismatches {
+        if problem != Some(mismatch.problem) {
+            problem = Some(mismatch.problem);
+            println!();
+            println!("{}:", mismatch.problem.heading(service));
+        }
+        match &mismatch.season {
+            Some(season) => print!("  {} - {}", mismatch.title, season),
+            None => print!("  {}", mismatch.title),
+        }
+        if mismatch.similar.is_empty() {
+            println!();
+        } else {
+            println!(" (similar: {})", mismatch.similar);
+        }
+    }
+}
+
+fn print_csv(mismatches: &[Mismatch]) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(io::stdout());
+    for mismatch in mismatches {
+        writer.serialize(mismatch)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+impl Runnable for UnmatchedCommand {
+    /// Print the mismatches.
+    fn run(&self) {
+        let config = app_config();
+        let cleaner =
+            block_on(Cleaner::from_config(&config)).expect("Could not set up API clients");
+        let service = cleaner.viewer.service_name();
+        let (watched, serieses) = block_on(join(
+            cleaner.viewer.all_watch_states(),
+            cleaner.all_series(),
+        ));
+        let watched = watched.expect("Fetching watch states");
+        let serieses = serieses.expect("Fetching serieses");
+
+        let mismatches = find_mismatches(&serieses, &watched);
+        match self.format {
+            OutputFormat::Table if mismatches.is_empty() => {
+                println!("Sonarr and {} agree on all shows and seasons.", service)
+            }
+            OutputFormat::Table => print_table(&mismatches, service),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&mismatches).expect("Printing JSON")
+            ),
+            OutputFormat::Csv => print_csv(&mismatches).expect("Printing CSV"),
+        }
+        if !mismatches.is_empty() {
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn series(title: &str, seasons: &[(u32, u128)]) -> Series {
+        let seasons: Vec<_> = seasons
+            .iter()
+            .map(|(number, size)| {
+                json!({
+                    "seasonNumber": number,
+                    "monitored": true,
+                    "statistics": {
+                        "episodeFileCount": 2,
+                        "totalEpisodeCount": 2,
+                        "episodeCount": 2,
+                        "previousAiring": null,
+                        "nextAiring": null,
+                        "sizeOnDisk": size,
+                    },
+                })
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "title": title,
+            "id": 1,
+            "tags": [],
+            "seasons": seasons,
+        }))
+        .unwrap()
+    }
+
+    fn watched(seasons: &[(&str, u32)]) -> HashMap<SeasonKey, WatchState> {
+        seasons
+            .iter()
+            .map(|(show, number)| {
+                (
+                    (show.to_string(), format!("Season {}", number)),
+                    WatchState {
+                        episodes: 2,
+                        viewed_episodes: 0,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn summary(mismatches: &[Mismatch]) -> Vec<(Problem, &str, Option<&str>, &str)> {
+        mismatches
+            .iter()
+            .map(|m| {
+                (
+                    m.problem,
+                    m.title.as_str(),
+                    m.season.as_deref(),
+                    m.similar.as_str(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matching_titles_and_seasons_agree() {
+        let serieses = [series("The Office", &[(1, 100), (2, 100)])];
+        let watched = watched(&[("The Office", 1), ("The Office", 2)]);
+        assert!(find_mismatches(&serieses, &watched).is_empty());
+    }
+
+    #[test]
+    fn titles_differing_in_case_do_not_match() {
+        let serieses = [series("The Office", &[(1, 100)])];
+        let watched = watched(&[("the office", 1)]);
+        assert_eq!(
+            summary(&find_mismatches(&serieses, &watched)),
+            vec![
+                (
+                    Problem::NoShowOnMediaServer,
+                    "The Office",
+                    None,
+                    "the office"
+                ),
+                (Problem::NoSeriesInSonarr, "the office", None, "The Office"),
+            ]
+        );
+    }
+
+    #[test]
+    fn titles_with_a_year_suffix_do_not_match() {
+        let serieses = [series("Doctor Who (2005)", &[(1, 100)])];
+        let watched = watched(&[("Doctor Who", 1)]);
+        assert_eq!(
+            summary(&find_mismatches(&serieses, &watched)),
+            vec![
+                (
+                    Problem::NoShowOnMediaServer,
+                    "Doctor Who (2005)",
+                    None,
+                    "Doctor Who"
+                ),
+                (
+                    Problem::NoSeriesInSonarr,
+                    "Doctor Who",
+                    None,
+                    "Doctor Who (2005)"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_seasons_missing_on_either_side() {
+        let serieses = [series("Show", &[(1, 100), (2, 100), (3, 0)])];
+        let watched = watched(&[("Show", 1), ("Show", 4)]);
+        assert_eq!(
+            summary(&find_mismatches(&serieses, &watched)),
+            vec![
+                (
+                    Problem::SeasonMissingOnMediaServer,
+                    "Show",
+                    Some("Season 2"),
+                    ""
+                ),
+                (Problem::SeasonMissingInSonarr, "Show", Some("Season 4"), ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_sonarr_series_without_files() {
+        let serieses = [series("Empty Show", &[(1, 0)]), series("Show", &[(1, 100)])];
+        let watched = watched(&[("Show", 1), ("Other Show", 1)]);
+        assert_eq!(
+            summary(&find_mismatches(&serieses, &watched)),
+            vec![(
+                Problem::NoSeriesInSonarr,
+                "Other Show",
+                None,
+                "Empty Show; Show"
+            )]
+        );
+    }
+}
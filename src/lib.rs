@@ -4,10 +4,21 @@
 
 #![deny(warnings, missing_docs, trivial_casts, unused_qualifications)]
 #![forbid(unsafe_code)]
+// abscissa_derive 0.4 puts the impls it derives inside a `const`:
+#![allow(non_local_definitions)]
 
 pub mod application;
+pub mod audit;
+pub mod cleaner;
 pub mod commands;
 pub mod config;
 pub mod error;
+pub mod logs;
+pub mod metrics;
+pub mod notify;
+pub mod plan;
 pub mod prelude;
+pub mod runtime;
 pub mod services;
+pub mod stats;
+pub mod web;
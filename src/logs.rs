@@ -0,0 +1,152 @@
+//! Writing log records to stderr.
+//!
+//! With the default `text` log format, each record is a line of text.
+//! With `format = "json"` in the `[logging]` section, each record is
+//! written as a single line of JSON instead. Messages about individual
+//! seasons (see [`season`]) carry the season's details as separate
+//! fields, so they can be shipped to a log stack and filtered there.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use abscissa_core::log::{self, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+
+use crate::config::LogFormat;
+use crate::plan::{Decision, PlanItem, Verdict};
+use crate::prelude::*;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The season that the record being logged on this thread is about.
+    static SEASON: RefCell<Option<SeasonFields>> = const { RefCell::new(None) };
+}
+
+/// Installs the logger, which writes records of `level` and above.
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(Box::leak(Box::new(Logger { level })))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Selects the format of the log records.
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::SeqCst);
+}
+
+/// The details of the season that a log message is about.
+#[derive(Debug, Clone, Serialize)]
+pub struct SeasonFields {
+    /// Sonarr ID of the series.
+    pub series_id: u32,
+
+    /// Title of the series.
+    pub title: String,
+
+    /// Number of the season.
+    pub season: u32,
+
+    /// Why the season is kept, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+
+    /// Space that the season occupies on disk.
+    pub size_bytes: u128,
+
+    /// The media server that the watch state came from.
+    pub service: &'static str,
+}
+
+impl SeasonFields {
+    /// Returns the fields for a season in the plan.
+    pub fn item(item: &PlanItem, service: &'static str) -> SeasonFields {
+        SeasonFields {
+            series_id: item.series_id,
+            title: item.title.clone(),
+            season: item.season,
+            reason: None,
+            size_bytes: item.size_bytes,
+            service,
+        }
+    }
+
+    /// Returns the fields for a season that the policy decided on.
+    pub fn decision(decision: &Decision<'_>, service: &'static str) -> SeasonFields {
+        SeasonFields {
+            reason: match &decision.verdict {
+                Verdict::Delete => None,
+                Verdict::Keep(reason) => Some(reason.label()),
+            },
+            ..SeasonFields::item(&decision.into(), service)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: DateTime<Utc>,
+    level: String,
+    target: &'a str,
+    message: String,
+    #[serde(flatten)]
+    fields: Option<&'a SeasonFields>,
+}
+
+/// Writes log records to stderr, in the format chosen with
+/// [`set_format`].
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = if JSON.load(Ordering::SeqCst) {
+            SEASON.with(|season| {
+                let season = season.borrow();
+                let line = Line {
+                    time: Utc::now(),
+                    level: record.level().to_string().to_lowercase(),
+                    target: record.target(),
+                    message: record.args().to_string(),
+                    fields: season.as_ref(),
+                };
+                serde_json::to_string(&line).unwrap_or_else(|e| {
+                    format!("{} (could not format as JSON: {})", record.args(), e)
+                })
+            })
+        } else {
+            format!(
+                "{} [{}] {}",
+                Local::now().format("%H:%M:%S"),
+                record.level(),
+                record.args()
+            )
+        };
+        let _ = writeln!(std::io::stderr(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Logs `message` about the season described by `fields`.
+pub fn season(level: Level, fields: &SeasonFields, message: fmt::Arguments<'_>) {
+    if !log_enabled!(level) {
+        return;
+    }
+    let outer = SEASON.with(|season| season.replace(Some(fields.clone())));
+    log!(level, "{}", message);
+    SEASON.with(|season| *season.borrow_mut() = outer);
+}
@@ -0,0 +1,129 @@
+//! Prometheus metrics about the cleaner's runs and API requests.
+//!
+//! Metrics can be written to a file for node_exporter's textfile
+//! collector (see `tv --metrics-file`), or served over HTTP at
+//! `/metrics` by the daemon.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+use tiny_http::{Header, Response, Server};
+
+use crate::prelude::*;
+
+lazy_static! {
+    /// Seasons that the retention policy looked at.
+    pub static ref SEASONS_EVALUATED: IntCounter = register_int_counter!(
+        "sonarr_plex_cleaner_seasons_evaluated_total",
+        "Seasons evaluated by the retention policy"
+    )
+    .unwrap();
+
+    /// Seasons that were eligible for deletion.
+    pub static ref SEASONS_ELIGIBLE: IntCounter = register_int_counter!(
+        "sonarr_plex_cleaner_seasons_eligible_total",
+        "Seasons eligible for deletion"
+    )
+    .unwrap();
+
+    /// Seasons that were deleted.
+    pub static ref SEASONS_DELETED: IntCounter = register_int_counter!(
+        "sonarr_plex_cleaner_seasons_deleted_total",
+        "Seasons deleted"
+    )
+    .unwrap();
+
+    /// Bytes freed by deleting seasons.
+    pub static ref BYTES_FREED: IntCounter = register_int_counter!(
+        "sonarr_plex_cleaner_freed_bytes_total",
+        "Bytes freed by deleting seasons"
+    )
+    .unwrap();
+
+    /// Seasons that were kept, by reason.
+    pub static ref SEASONS_KEPT: IntCounterVec = register_int_counter_vec!(
+        "sonarr_plex_cleaner_seasons_kept_total",
+        "Seasons that were kept, by reason",
+        &["reason"]
+    )
+    .unwrap();
+
+    /// Latency of API requests, by service.
+    pub static ref API_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "sonarr_plex_cleaner_api_request_duration_seconds",
+        "Latency of API requests",
+        &["service"]
+    )
+    .unwrap();
+
+    /// Failed API requests, by service.
+    pub static ref API_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "sonarr_plex_cleaner_api_errors_total",
+        "API requests that failed or returned an error status",
+        &["service"]
+    )
+    .unwrap();
+}
+
+/// Records the latency of a request to `service`, and whether it
+/// failed.
+pub fn observe(service: &str, elapsed: Duration, result: &reqwest::Result<reqwest::Response>) {
+    API_REQUEST_DURATION
+        .with_label_values(&[service])
+        .observe(elapsed.as_secs_f64());
+    let failed = match result {
+        Ok(response) => response.status().is_client_error() || response.status().is_server_error(),
+        Err(_) => true,
+    };
+    if failed {
+        API_ERRORS.with_label_values(&[service]).inc();
+    }
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn render() -> Result<Vec<u8>> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(buffer)
+}
+
+/// Writes all metrics to `path`, replacing it atomically so that the
+/// textfile collector never sees a partial file.
+pub fn write_textfile(path: &Path) -> Result<()> {
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, render()?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Serves the metrics at `/metrics` on `addr`. Never returns unless
+/// the listening socket fails.
+pub fn serve(addr: SocketAddr) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| anyhow!("{}", e))?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    for request in server.incoming_requests() {
+        let response = if request.url() == "/metrics" {
+            match render() {
+                Ok(body) => Response::from_data(body).with_header(
+                    Header::from_bytes(&b"Content-Type"[..], TextEncoder::new().format_type())
+                        .expect("valid header"),
+                ),
+                Err(e) => Response::from_string(e.to_string()).with_status_code(500),
+            }
+        } else {
+            Response::from_string("Not found").with_status_code(404)
+        };
+        if let Err(e) = request.respond(response) {
+            warn!("Could not respond to metrics request: {}", e);
+        }
+    }
+    Ok(())
+}
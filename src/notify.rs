@@ -0,0 +1,257 @@
+//! Sending a summary of each run to webhooks and by email.
+//!
+//! Messages are rendered from a template, in which the following
+//! placeholders get replaced:
+//!
+//! * `{{deleted_count}}`, `{{partially_deleted_count}}`,
+//!   `{{would_delete_count}}`, `{{skipped_count}}` and
+//!   `{{error_count}}`: the number of seasons (or errors) in each
+//!   category.
+//! * `{{bytes_freed}}`: the space freed up by the (partially) deleted
+//!   seasons.
+//! * `{{deleted}}`, `{{partially_deleted}}`, `{{would_delete}}`,
+//!   `{{skipped}}`: one line per season in the category.
+//! * `{{errors}}`: one line per error.
+//!
+//! Seasons that will soon become eligible for deletion are announced
+//! in a separate message, rendered from the `upcoming_template`, in
+//! which `{{upcoming_count}}` and `{{upcoming}}` (one line per season)
+//! get replaced.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use humantime::format_duration;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use reqwest::header::HeaderMap;
+use secrecy::ExposeSecret;
+use serde_json::{json, Value};
+use tokio::task::spawn_blocking;
+use tokio::time::timeout;
+
+use crate::cleaner::RunSummary;
+use crate::config::{NotificationSettings, SmtpSettings, WebhookFormat, WebhookNotification};
+use crate::plan::{format_size, PlanItem, Upcoming};
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::services::http::HttpClient;
+
+/// The template used if none is configured.
+pub const DEFAULT_TEMPLATE: &str = "sonarr-plex-cleaner: deleted {{deleted_count}} seasons, \
+freeing {{bytes_freed}}.
+{{deleted}}{{partially_deleted}}{{would_delete}}{{skipped}}{{errors}}";
+
+/// The template for upcoming deletions used if none is configured.
+pub const DEFAULT_UPCOMING_TEMPLATE: &str =
+    "sonarr-plex-cleaner: {{upcoming_count}} seasons will be deleted soon. \
+Tag them to keep them around.
+{{upcoming}}";
+
+/// Discord refuses messages longer than this.
+const DISCORD_MAX_LENGTH: usize = 2000;
+
+fn item_lines(heading: &str, items: &[PlanItem]) -> String {
+    if items.is_empty() {
+        return String::new();
+    }
+    let mut lines = format!("{}:\n", heading);
+    for item in items {
+        lines.push_str(&format!(
+            "* {} S{:02} ({})\n",
+            item.title,
+            item.season,
+            format_size(item.size_bytes)
+        ));
+    }
+    lines
+}
+
+/// Renders `template` with the values from `summary`.
+pub fn render(template: &str, summary: &RunSummary) -> String {
+    let errors = if summary.errors.is_empty() {
+        String::new()
+    } else {
+        format!("Errors:\n* {}\n", summary.errors.join("\n* "))
+    };
+    template
+        .replace("{{deleted_count}}", &summary.deleted.len().to_string())
+        .replace(
+            "{{partially_deleted_count}}",
+            &summary.partially_deleted.len().to_string(),
+        )
+        .replace(
+            "{{would_delete_count}}",
+            &summary.would_delete.len().to_string(),
+        )
+        .replace("{{skipped_count}}", &summary.skipped.len().to_string())
+        .replace("{{error_count}}", &summary.errors.len().to_string())
+        .replace("{{bytes_freed}}", &format_size(summary.bytes_freed()))
+        .replace("{{deleted}}", &item_lines("Deleted", &summary.deleted))
+        .replace(
+            "{{partially_deleted}}",
+            &item_lines("Partially deleted", &summary.partially_deleted),
+        )
+        .replace(
+            "{{would_delete}}",
+            &item_lines("Would delete (dry run)", &summary.would_delete),
+        )
+        .replace("{{skipped}}", &item_lines("Skipped", &summary.skipped))
+        .replace("{{errors}}", &errors)
+}
+
+/// Renders `template` with the upcoming deletions in `upcoming`.
+pub fn render_upcoming(template: &str, upcoming: &[Upcoming], now: DateTime<Utc>) -> String {
+    let mut lines = String::new();
+    for season in upcoming {
+        lines.push_str(&format!(
+            "* {} S{:02} will be removed in {} days ({})\n",
+            season.item.title,
+            season.item.season,
+            (season.deletable_at - now).num_days(),
+            season.deletable_at.format("%Y-%m-%d"),
+        ));
+    }
+    template
+        .replace("{{upcoming_count}}", &upcoming.len().to_string())
+        .replace("{{upcoming}}", &lines)
+}
+
+/// Sends run summaries to the configured destinations.
+pub struct Notifier {
+    settings: NotificationSettings,
+
+    /// A client for each of the configured webhooks, in order.
+    webhooks: Vec<HttpClient>,
+}
+
+impl Notifier {
+    /// Constructs a notifier from the notification settings.
+    pub fn from_config(settings: &NotificationSettings) -> Result<Notifier> {
+        let webhooks = settings
+            .webhooks
+            .iter()
+            .map(|webhook| HttpClient::new("webhook", &webhook.server(), HeaderMap::new()))
+            .collect::<Result<_>>()?;
+        Ok(Notifier {
+            settings: settings.clone(),
+            webhooks,
+        })
+    }
+
+    /// Sends `summary` to every configured destination, followed by
+    /// a separate message about upcoming deletions. Failures are
+    /// logged, but don't stop delivery to the other destinations.
+    pub fn send(&self, summary: &RunSummary) {
+        if summary.is_empty() {
+            debug!("Nothing happened, not sending a run summary");
+        } else {
+            let template = self
+                .settings
+                .template
+                .as_deref()
+                .unwrap_or(DEFAULT_TEMPLATE);
+            let message = render(template, summary);
+            self.deliver(
+                None,
+                &message,
+                json!({
+                    "message": message,
+                    "bytes_freed": summary.bytes_freed(),
+                    "summary": summary,
+                }),
+            );
+        }
+
+        if !summary.upcoming.is_empty() {
+            let template = self
+                .settings
+                .upcoming_template
+                .as_deref()
+                .unwrap_or(DEFAULT_UPCOMING_TEMPLATE);
+            let message = render_upcoming(template, &summary.upcoming, Utc::now());
+            self.deliver(
+                Some("upcoming deletions"),
+                &message,
+                json!({
+                    "message": message,
+                    "upcoming": summary.upcoming,
+                }),
+            );
+        }
+    }
+
+    /// Sends a message to all destinations. JSON webhooks receive
+    /// `details` as the payload.
+    fn deliver(&self, topic: Option<&str>, message: &str, details: Value) {
+        for (webhook, client) in self.settings.webhooks.iter().zip(&self.webhooks) {
+            if let Err(e) = block_on(send_webhook(client, webhook, message, &details)) {
+                error!(
+                    "Could not notify webhook {}: {}",
+                    webhook.url.host_str().unwrap_or(""),
+                    e
+                );
+            }
+        }
+        if let Some(smtp) = &self.settings.smtp {
+            if let Err(e) = block_on(send_mail(smtp, topic, message)) {
+                error!("Could not send notification email: {}", e);
+            }
+        }
+    }
+}
+
+async fn send_webhook(
+    client: &HttpClient,
+    webhook: &WebhookNotification,
+    message: &str,
+    details: &Value,
+) -> Result<()> {
+    let body = match webhook.format {
+        WebhookFormat::Json => details.clone(),
+        WebhookFormat::Slack => json!({ "text": message }),
+        WebhookFormat::Discord => {
+            let content: String = message.chars().take(DISCORD_MAX_LENGTH).collect();
+            json!({ "content": content })
+        }
+    };
+    client
+        .send(|c| c.post(webhook.url.clone()).json(&body))
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn send_mail(smtp: &SmtpSettings, topic: Option<&str>, message: &str) -> Result<()> {
+    let subject = match topic {
+        Some(topic) => format!("{}: {}", smtp.subject, topic),
+        None => smtp.subject.clone(),
+    };
+    let mut email = Message::builder().from(smtp.from.parse()?).subject(subject);
+    for to in smtp.to.iter() {
+        email = email.to(to.parse()?);
+    }
+    let email = email.body(message.to_string())?;
+
+    let mut transport = SmtpTransport::starttls_relay(&smtp.server)?.timeout(Some(smtp.timeout));
+    if let Some(port) = smtp.port {
+        transport = transport.port(port);
+    }
+    match (&smtp.username, &smtp.password) {
+        (Some(user), Some(password)) => {
+            transport = transport.credentials(Credentials::new(
+                user.to_string(),
+                password.expose_secret().as_str().to_string(),
+            ));
+        }
+        (None, None) => {}
+        _ => return Err(anyhow!("SMTP needs both a username and a password")),
+    }
+    // The transport blocks, so it gets a thread of its own. Its
+    // timeout applies to each command; this one to the whole email.
+    let transport = transport.build();
+    let sending = spawn_blocking(move || transport.send(&email));
+    timeout(smtp.timeout, sending)
+        .await
+        .map_err(|_| anyhow!("timed out after {}", format_duration(smtp.timeout)))???;
+    Ok(())
+}
@@ -0,0 +1,523 @@
+//! Deciding which TV seasons get cleaned up.
+//!
+//! The retention policy looks at every season that Sonarr knows
+//! about, combines it with the watched state reported by the media
+//! server, and arrives at a [`Decision`] for each season.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use abscissa_core::log::Level;
+use anyhow::{anyhow, Result};
+use byte_unit::{Byte, ByteUnit};
+use chrono::{DateTime, Duration, Utc};
+use humantime::format_duration;
+use serde::Serialize;
+
+use crate::config::RetentionSettings;
+use crate::logs::{self, SeasonFields};
+use crate::services::sonarr::{self, Season, Series, TagId};
+use crate::services::viewer::{SeasonKey, WatchState};
+
+/// Formats a number of bytes for humans.
+pub fn format_size(bytes: u128) -> String {
+    Byte::from_bytes(bytes)
+        .get_adjusted_unit(ByteUnit::GiB)
+        .to_string()
+}
+
+/// Formats a (non-negative) chrono duration for humans.
+pub fn format_age(duration: Duration) -> String {
+    format_duration(duration.to_std().expect("duration out of range")).to_string()
+}
+
+/// Returns the key under which the media server tracks a Sonarr season.
+pub fn season_key(series: &Series, season: &Season) -> SeasonKey {
+    (
+        series.title.clone(),
+        format!("Season {}", season.season_number),
+    )
+}
+
+/// Returns up to `count` of the `candidates` that are most similar to
+/// `title`, most similar first. Useful to find the show that the
+/// media server knows under a slightly different title.
+pub fn similar_titles<'a>(
+    title: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+    count: usize,
+) -> Vec<&'a str> {
+    let title = title.to_lowercase();
+    let candidates: BTreeSet<&str> = candidates.into_iter().collect();
+    let mut scored: Vec<(f64, &str)> = candidates
+        .into_iter()
+        .map(|c| (strsim::normalized_levenshtein(&title, &c.to_lowercase()), c))
+        .filter(|(score, _)| *score > 0.3)
+        .collect();
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    scored.into_iter().take(count).map(|(_, c)| c).collect()
+}
+
+/// The retention policy, resolved against the Sonarr server.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Label and ID of the tag that marks a series as manually managed.
+    pub retain_tag: Option<(String, TagId)>,
+
+    /// How long a season is kept after its last episode aired.
+    pub retain_duration: Duration,
+
+    /// How long before a season becomes eligible for deletion to
+    /// warn about it.
+    pub warn_before: Duration,
+}
+
+impl Policy {
+    /// Resolves the retention settings, looking up the retain tag in Sonarr.
+    pub async fn from_config(
+        conf: &RetentionSettings,
+        sonarr: &sonarr::SonarrClient,
+    ) -> Result<Policy> {
+        let retain_tag = match &conf.retain_tag {
+            None => None,
+            Some(tag_name) => {
+                let tags = sonarr
+                    .fetch_tags()
+                    .await
+                    .map_err(|e| anyhow!("sonarr tags: {}", e))?;
+                let tag = tags
+                    .get(tag_name)
+                    .ok_or_else(|| anyhow!("Tag {:?} not found in {:?}", tag_name, tags))?;
+                Some((tag.label.to_string(), tag.id))
+            }
+        };
+        let retain_duration = Duration::from_std(conf.retain_duration)
+            .map_err(|_| anyhow!("Weird retain duration (past max chrono duration?)"))?;
+        let warn_before = Duration::from_std(conf.warn_before)
+            .map_err(|_| anyhow!("Weird warn_before duration (past max chrono duration?)"))?;
+        Ok(Policy {
+            retain_tag,
+            retain_duration,
+            warn_before,
+        })
+    }
+
+    /// Decides what should happen to a single season.
+    pub fn decide(
+        &self,
+        series: &Series,
+        season: &Season,
+        watch_state: Option<&WatchState>,
+        now: DateTime<Utc>,
+    ) -> Verdict {
+        if let Some((name, id)) = &self.retain_tag {
+            if series.tags.contains(id) {
+                return Verdict::Keep(Reason::Retained(name.to_string()));
+            }
+        }
+        if !watch_state.map(WatchState::fully_watched).unwrap_or(false) {
+            return Verdict::Keep(Reason::Unwatched);
+        }
+        let stats = &season.statistics;
+        if stats.next_airing.is_some() {
+            return Verdict::Keep(Reason::StillAiring);
+        }
+        match stats.previous_airing {
+            None => return Verdict::Keep(Reason::NeverAired),
+            Some(air) if air + self.retain_duration >= now => {
+                return Verdict::Keep(Reason::TooRecent {
+                    age: now - air,
+                    desired: self.retain_duration,
+                });
+            }
+            Some(_) => {}
+        }
+        if stats.size_on_disk == 0 {
+            return Verdict::Keep(Reason::NothingOnDisk);
+        }
+        Verdict::Delete
+    }
+}
+
+/// Why a season is kept around.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    /// The series is tagged with the (named) retain tag.
+    Retained(String),
+
+    /// The season has episodes that haven't been watched, or the
+    /// media server doesn't know about it.
+    Unwatched,
+
+    /// There are more episodes announced for the season.
+    StillAiring,
+
+    /// No episode of the season has aired yet.
+    NeverAired,
+
+    /// The season's last episode aired more recently than the
+    /// retention period allows.
+    TooRecent {
+        /// Time since the last episode aired.
+        age: Duration,
+
+        /// The configured retention period.
+        desired: Duration,
+    },
+
+    /// There are no files for the season on disk.
+    NothingOnDisk,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Retained(tag) => write!(f, "it is tagged {:?}", tag),
+            Reason::Unwatched => write!(f, "it is unwatched"),
+            Reason::StillAiring => write!(f, "it is still airing"),
+            Reason::NeverAired => write!(f, "it has not aired yet"),
+            Reason::TooRecent { age, desired } => write!(
+                f,
+                "age:{} < desired:{}",
+                format_age(*age),
+                format_age(*desired)
+            ),
+            Reason::NothingOnDisk => write!(f, "it has no files on disk"),
+        }
+    }
+}
+
+impl Reason {
+    /// A short, stable name for the reason, for use in metrics and
+    /// machine-readable output.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Reason::Retained(_) => "retained",
+            Reason::Unwatched => "unwatched",
+            Reason::StillAiring => "still_airing",
+            Reason::NeverAired => "never_aired",
+            Reason::TooRecent { .. } => "too_recent",
+            Reason::NothingOnDisk => "nothing_on_disk",
+        }
+    }
+}
+
+/// What should happen to a season.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// The season is eligible for deletion.
+    Delete,
+
+    /// The season should be kept, for the given reason.
+    Keep(Reason),
+}
+
+/// The verdict for a single Sonarr season, along with the evidence
+/// that led to it.
+#[derive(Debug)]
+pub struct Decision<'a> {
+    /// The series that the season belongs to.
+    pub series: &'a Series,
+
+    /// The season itself.
+    pub season: &'a Season,
+
+    /// The media server's watched state for the season, if it knows
+    /// the season.
+    pub watch_state: Option<WatchState>,
+
+    /// What should happen to the season.
+    pub verdict: Verdict,
+}
+
+impl<'a> Decision<'a> {
+    /// True if the season should be deleted.
+    pub fn is_eligible(&self) -> bool {
+        self.verdict == Verdict::Delete
+    }
+
+    /// Returns when the season will become eligible for deletion, if
+    /// the only thing keeping it around is the retention period.
+    pub fn deletable_at(&self, policy: &Policy) -> Option<DateTime<Utc>> {
+        match self.verdict {
+            Verdict::Keep(Reason::TooRecent { .. }) if self.season.statistics.size_on_disk > 0 => {
+                Some(self.season.statistics.previous_airing? + policy.retain_duration)
+            }
+            _ => None,
+        }
+    }
+
+    /// Logs why a season is kept, at the level appropriate for the reason.
+    pub fn log(&self, service: &'static str) {
+        let reason = match &self.verdict {
+            Verdict::Delete => return,
+            Verdict::Keep(reason) => reason,
+        };
+        let level = match reason {
+            Reason::Retained(_) | Reason::Unwatched => Level::Debug,
+            Reason::StillAiring if self.season.statistics.previous_airing.is_some() => Level::Info,
+            Reason::TooRecent { .. } => Level::Info,
+            _ => return,
+        };
+        logs::season(
+            level,
+            &SeasonFields::decision(self, service),
+            format_args!(
+                "Skipping {} - Season {:?} because {}",
+                self.series.title, self.season.season_number, reason
+            ),
+        );
+    }
+}
+
+/// A season that is eligible for deletion, detached from the Sonarr
+/// data it was computed from.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanItem {
+    /// Sonarr ID of the series.
+    pub series_id: u32,
+
+    /// Title of the series.
+    pub title: String,
+
+    /// Number of the season.
+    pub season: u32,
+
+    /// Space that deleting the season frees up.
+    pub size_bytes: u128,
+
+    /// When the season's last episode aired.
+    pub previous_airing: Option<DateTime<Utc>>,
+
+    /// The media server's watched state for the season.
+    pub watch_state: Option<WatchState>,
+}
+
+impl<'a> From<&Decision<'a>> for PlanItem {
+    fn from(decision: &Decision<'a>) -> PlanItem {
+        PlanItem {
+            series_id: decision.series.id,
+            title: decision.series.title.clone(),
+            season: decision.season.season_number,
+            size_bytes: decision.season.statistics.size_on_disk,
+            previous_airing: decision.season.statistics.previous_airing,
+            watch_state: decision.watch_state,
+        }
+    }
+}
+
+/// A season that will soon become eligible for deletion.
+#[derive(Debug, Clone, Serialize)]
+pub struct Upcoming {
+    /// The season.
+    #[serde(flatten)]
+    pub item: PlanItem,
+
+    /// When the season becomes eligible for deletion.
+    pub deletable_at: DateTime<Utc>,
+}
+
+/// Returns the seasons that become eligible for deletion within the
+/// policy's `warn_before` period.
+pub fn upcoming(policy: &Policy, decisions: &[Decision<'_>], now: DateTime<Utc>) -> Vec<Upcoming> {
+    if policy.warn_before == Duration::zero() {
+        return vec![];
+    }
+    decisions
+        .iter()
+        .filter_map(|decision| {
+            let deletable_at = decision.deletable_at(policy)?;
+            if deletable_at > now + policy.warn_before {
+                return None;
+            }
+            Some(Upcoming {
+                item: decision.into(),
+                deletable_at,
+            })
+        })
+        .collect()
+}
+
+/// Decides the fate of every season of every series.
+pub fn evaluate<'a>(
+    policy: &Policy,
+    serieses: &'a [Series],
+    watched: &HashMap<SeasonKey, WatchState>,
+    now: DateTime<Utc>,
+) -> Vec<Decision<'a>> {
+    serieses
+        .iter()
+        .flat_map(|series| {
+            series.seasons.iter().map(move |season| {
+                let watch_state = watched.get(&season_key(series, season)).cloned();
+                let verdict = policy.decide(series, season, watch_state.as_ref(), now);
+                Decision {
+                    series,
+                    season,
+                    watch_state,
+                    verdict,
+                }
+            })
+        })
+        .collect()
+}
+
+/// Groups the seasons that are eligible for deletion by their series.
+pub fn eligible_by_series<'a, 'd>(
+    decisions: &'d [Decision<'a>],
+) -> Vec<(&'a Series, Vec<&'d Decision<'a>>)> {
+    let mut grouped: Vec<(&'a Series, Vec<&'d Decision<'a>>)> = vec![];
+    for decision in decisions.iter().filter(|d| d.is_eligible()) {
+        match grouped.last_mut() {
+            Some((series, seasons)) if series.id == decision.series.id => seasons.push(decision),
+            _ => grouped.push((decision.series, vec![decision])),
+        }
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NOW: &str = "2020-06-01T00:00:00Z";
+
+    fn now() -> DateTime<Utc> {
+        NOW.parse().unwrap()
+    }
+
+    fn series(tags: &[u32], previous_airing: Option<&str>, next_airing: Option<&str>) -> Series {
+        serde_json::from_value(json!({
+            "title": "Show",
+            "id": 1,
+            "tags": tags,
+            "seasons": [{
+                "seasonNumber": 1,
+                "monitored": true,
+                "statistics": {
+                    "episodeFileCount": 2,
+                    "totalEpisodeCount": 2,
+                    "episodeCount": 2,
+                    "previousAiring": previous_airing,
+                    "nextAiring": next_airing,
+                    "sizeOnDisk": 1000,
+                },
+            }],
+        }))
+        .unwrap()
+    }
+
+    fn policy(retain_tag: Option<u32>, retain_days: i64) -> Policy {
+        Policy {
+            retain_tag: retain_tag.map(|id| {
+                (
+                    "retain".to_string(),
+                    serde_json::from_value(json!(id)).unwrap(),
+                )
+            }),
+            retain_duration: Duration::days(retain_days),
+            warn_before: Duration::zero(),
+        }
+    }
+
+    const WATCHED: WatchState = WatchState {
+        episodes: 2,
+        viewed_episodes: 2,
+    };
+
+    fn decide(policy: &Policy, series: &Series, watch_state: Option<&WatchState>) -> Verdict {
+        policy.decide(series, &series.seasons[0], watch_state, now())
+    }
+
+    #[test]
+    fn deletes_watched_seasons_past_retention() {
+        let series = series(&[], Some("2020-01-01T00:00:00Z"), None);
+        assert_eq!(
+            decide(&policy(None, 14), &series, Some(&WATCHED)),
+            Verdict::Delete
+        );
+    }
+
+    #[test]
+    fn keeps_retained_series() {
+        let series = series(&[7], Some("2020-01-01T00:00:00Z"), None);
+        assert_eq!(
+            decide(&policy(Some(7), 14), &series, Some(&WATCHED)),
+            Verdict::Keep(Reason::Retained("retain".to_string()))
+        );
+        assert_eq!(
+            decide(&policy(Some(8), 14), &series, Some(&WATCHED)),
+            Verdict::Delete
+        );
+    }
+
+    #[test]
+    fn keeps_unwatched_seasons() {
+        let series = series(&[], Some("2020-01-01T00:00:00Z"), None);
+        let partly = WatchState {
+            episodes: 2,
+            viewed_episodes: 1,
+        };
+        assert_eq!(
+            decide(&policy(None, 14), &series, Some(&partly)),
+            Verdict::Keep(Reason::Unwatched)
+        );
+        assert_eq!(
+            decide(&policy(None, 14), &series, None),
+            Verdict::Keep(Reason::Unwatched)
+        );
+    }
+
+    #[test]
+    fn keeps_airing_and_unaired_seasons() {
+        let airing = series(
+            &[],
+            Some("2020-01-01T00:00:00Z"),
+            Some("2020-07-01T00:00:00Z"),
+        );
+        assert_eq!(
+            decide(&policy(None, 14), &airing, Some(&WATCHED)),
+            Verdict::Keep(Reason::StillAiring)
+        );
+        let unaired = series(&[], None, None);
+        assert_eq!(
+            decide(&policy(None, 14), &unaired, Some(&WATCHED)),
+            Verdict::Keep(Reason::NeverAired)
+        );
+    }
+
+    #[test]
+    fn keeps_seasons_within_retention() {
+        let series = series(&[], Some("2020-05-25T00:00:00Z"), None);
+        assert_eq!(
+            decide(&policy(None, 14), &series, Some(&WATCHED)),
+            Verdict::Keep(Reason::TooRecent {
+                age: Duration::days(7),
+                desired: Duration::days(14),
+            })
+        );
+        // exactly at the end of the retention period:
+        assert_eq!(
+            decide(&policy(None, 7), &series, Some(&WATCHED)),
+            Verdict::Keep(Reason::TooRecent {
+                age: Duration::days(7),
+                desired: Duration::days(7),
+            })
+        );
+        assert_eq!(
+            decide(&policy(None, 6), &series, Some(&WATCHED)),
+            Verdict::Delete
+        );
+    }
+
+    #[test]
+    fn keeps_seasons_without_files() {
+        let mut series = series(&[], Some("2020-01-01T00:00:00Z"), None);
+        series.seasons[0].statistics.size_on_disk = 0;
+        assert_eq!(
+            decide(&policy(None, 14), &series, Some(&WATCHED)),
+            Verdict::Keep(Reason::NothingOnDisk)
+        );
+    }
+}
@@ -0,0 +1,24 @@
+//! The async runtime that drives the API clients.
+//!
+//! Commands, the web UI and the daemon are synchronous; they run the
+//! async API calls to completion with [`block_on`]. All calls share
+//! one runtime, so that connection pools outlive a single call.
+
+use std::future::Future;
+
+use lazy_static::lazy_static;
+use tokio::runtime::{Builder, Runtime};
+
+lazy_static! {
+    static ref RUNTIME: Runtime = Builder::new_multi_thread()
+        .thread_name("sonarr-plex-cleaner-io")
+        .enable_all()
+        .build()
+        .expect("could not start the async runtime");
+}
+
+/// Runs `future` to completion on the shared runtime, blocking the
+/// current thread. Must not be called from async code.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.block_on(future)
+}
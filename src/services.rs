@@ -3,6 +3,9 @@
 //! In this module are all the types & methods we need to run the
 //! cleaner against media indexers & "watched" state keepers.
 
+pub mod http;
 pub mod jellyfin;
 pub mod plex;
+pub mod replay;
 pub mod sonarr;
+pub mod viewer;
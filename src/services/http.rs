@@ -0,0 +1,221 @@
+//! The HTTP layer that all API clients share.
+//!
+//! It applies each server's [`HttpSettings`]: requests that can
+//! safely be sent again are retried with exponential backoff, and the
+//! number and rate of requests to a server can be limited.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, RequestBuilder, Response, Url};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::sleep;
+use url::ParseError;
+
+use crate::config::{HttpSettings, ServerSettings};
+use crate::metrics;
+use crate::prelude::*;
+use crate::services::replay::Transport;
+
+/// Limits how many requests to a server are in flight, and how
+/// quickly they are started.
+#[derive(Debug)]
+struct Limiter {
+    slots: Option<Semaphore>,
+    interval: Option<Duration>,
+    next_start: Mutex<Instant>,
+}
+
+impl Limiter {
+    fn new(settings: &HttpSettings) -> Limiter {
+        Limiter {
+            slots: settings.max_concurrent_requests.map(Semaphore::new),
+            interval: settings
+                .requests_per_second
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            next_start: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until a request may be sent. The returned permit, if
+    /// any, holds a slot until it is dropped.
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let permit = match &self.slots {
+            Some(slots) => Some(slots.acquire().await.expect("semaphore is never closed")),
+            None => None,
+        };
+        if let Some(interval) = self.interval {
+            let wait = {
+                let mut next_start = self.next_start.lock().unwrap();
+                let now = Instant::now();
+                let start = (*next_start).max(now);
+                *next_start = start + interval;
+                start - now
+            };
+            sleep(wait).await;
+        }
+        permit
+    }
+}
+
+/// Joins `path` onto the `base` URL of a server. Unlike
+/// [`Url::join`], this keeps the whole path of `base` (e.g. the
+/// prefix that a reverse proxy serves the API under), whether or not
+/// it ends in a slash and whether or not `path` starts with one.
+pub fn join_url(base: &Url, path: &str) -> Result<Url, ParseError> {
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        let prefix = format!("{}/", base.path());
+        base.set_path(&prefix);
+    }
+    base.join(path.trim_start_matches('/'))
+}
+
+/// True if sending a request with `method` twice has the same effect
+/// as sending it once.
+fn is_idempotent(method: &Method) -> bool {
+    *method == Method::GET
+        || *method == Method::HEAD
+        || *method == Method::PUT
+        || *method == Method::DELETE
+        || *method == Method::OPTIONS
+}
+
+/// An HTTP client for one server.
+#[derive(Debug)]
+pub struct HttpClient {
+    service: &'static str,
+    client: Client,
+    retries: u32,
+    retry_backoff: Duration,
+    limiter: Limiter,
+    transport: Transport,
+}
+
+impl HttpClient {
+    /// Constructs a client for `service` (used in logs and metrics)
+    /// that sends `headers` with every request.
+    pub fn new<T>(
+        service: &'static str,
+        conf: &ServerSettings<T>,
+        headers: HeaderMap,
+    ) -> anyhow::Result<HttpClient> {
+        Ok(HttpClient {
+            service,
+            transport: Transport::from_env(service, conf, &headers)?,
+            client: conf.client_builder(headers)?.build()?,
+            retries: conf.http.retries,
+            retry_backoff: conf.http.retry_backoff,
+            limiter: Limiter::new(&conf.http),
+        })
+    }
+
+    /// Sends the request that `build` makes with the underlying
+    /// client. If the connection fails or the server responds with a
+    /// 5xx status, idempotent requests are built and sent again, up to
+    /// the configured number of retries. When replaying, the recorded
+    /// response is returned without sending anything.
+    pub async fn send<F>(&self, build: F) -> reqwest::Result<Response>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let request = build(&self.client).build()?;
+            let method = request.method().clone();
+            let url = request.url().clone();
+
+            if let Transport::Replay(replayer) = &self.transport {
+                return Ok(replayer.replay(&method, &url));
+            }
+
+            let result = {
+                let _permit = self.limiter.acquire().await;
+                let start = Instant::now();
+                let result = self.client.execute(request).await;
+                let elapsed = start.elapsed();
+                metrics::observe(self.service, elapsed, &result);
+                match &result {
+                    Ok(response) => debug!(
+                        "{}: {} {} -> {} in {:?}",
+                        self.service,
+                        method,
+                        url,
+                        response.status(),
+                        elapsed
+                    ),
+                    Err(e) => debug!(
+                        "{}: {} {} failed after {:?}: {}",
+                        self.service, method, url, elapsed, e
+                    ),
+                }
+                result
+            };
+            let result = match (result, &self.transport) {
+                (Ok(response), Transport::Record(recorder)) => {
+                    recorder.record(&method, &url, response).await
+                }
+                (result, _) => result,
+            };
+
+            let retriable = is_idempotent(&method)
+                && match &result {
+                    Ok(response) => response.status().is_server_error(),
+                    Err(e) => e.status().is_none(),
+                };
+            if !retriable || attempt >= self.retries {
+                return result;
+            }
+            let delay = self.retry_backoff * 2u32.saturating_pow(attempt);
+            match &result {
+                Ok(response) => info!(
+                    "{}: {} {} returned {}, retrying in {:?}",
+                    self.service,
+                    method,
+                    url,
+                    response.status(),
+                    delay
+                ),
+                Err(e) => info!(
+                    "{}: {} {} failed ({}), retrying in {:?}",
+                    self.service, method, url, e, delay
+                ),
+            }
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(base: &str, path: &str) -> String {
+        join_url(&Url::parse(base).unwrap(), path)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn join_url_keeps_the_base_path() {
+        for base in &["http://proxy/sonarr/api/", "http://proxy/sonarr/api"] {
+            for path in &["series", "/series"] {
+                assert_eq!(joined(base, path), "http://proxy/sonarr/api/series");
+            }
+        }
+    }
+
+    #[test]
+    fn join_url_onto_the_root() {
+        assert_eq!(
+            joined("http://sonarr:8989", "api/v3/series"),
+            "http://sonarr:8989/api/v3/series"
+        );
+        assert_eq!(
+            joined("http://sonarr:8989/", "/episodefile?seriesId=1"),
+            "http://sonarr:8989/episodefile?seriesId=1"
+        );
+    }
+}
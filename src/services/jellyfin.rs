@@ -6,6 +6,8 @@ use anyhow::{anyhow, Result};
 use serde::Deserialize;
 
 use crate::config;
+use crate::services::http::{join_url, HttpClient};
+use crate::services::viewer::WatchState;
 
 /// Makes requests to a jellyfin/emby server API.
 ///
@@ -20,30 +22,170 @@ pub struct JellyfinClient {
 
 impl JellyfinClient {
     /// Construct a new client
-    pub fn from_config(conf: &config::JellyfinSettings) -> Result<JellyfinClient> {
-        let (base_url, auth_headers) = conf.server.jellyfin_base();
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::RedirectPolicy::none())
-            .default_headers(auth_headers)
-            .build()?;
-        let client = BaseClient { base_url, client };
-        let user_id = client.get_user_id(&conf.user)?;
+    pub async fn from_config(conf: &config::JellyfinSettings) -> Result<JellyfinClient> {
+        let client = BaseClient::from_config(&conf.server)?;
+        let user_id = client.get_user_id(&conf.user).await?;
         Ok(JellyfinClient { client, user_id })
     }
 
+    /// Returns information about the server, without looking up a user.
+    pub async fn system_info(
+        conf: &config::ServerSettings<config::Jellyfin>,
+    ) -> Result<SystemInfo> {
+        BaseClient::from_config(conf)?.system_info().await
+    }
+
+    /// Returns the names of all users on the server.
+    pub async fn user_names(
+        conf: &config::ServerSettings<config::Jellyfin>,
+    ) -> Result<Vec<String>> {
+        Ok(BaseClient::from_config(conf)?
+            .users()
+            .await?
+            .into_iter()
+            .map(|user| user.name)
+            .collect())
+    }
+
     /// Retrieve all TV seasons available to the given user on the server.
-    pub fn all_tv_seasons(&self) -> Result<Vec<Season>> {
+    pub async fn all_tv_seasons(&self) -> Result<Vec<Season>> {
         let url = self.client.build_url(["/Users", &self.user_id, "Items"]);
+        let query = [
+            ("Recursive", "true"),
+            ("includeItemTypes", "Season"),
+            ("Fields", "ChildCount"),
+        ];
         let resp: SeasonResponse = self
             .client
+            .http
+            .send(|c| c.get(url.clone()).query(&query))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp.items)
+    }
+
+    /// Returns the name and ID of every series available to the user.
+    pub async fn all_series(&self) -> Result<Vec<(String, String)>> {
+        let url = self.client.build_url(["/Users", &self.user_id, "Items"]);
+        let query = [("Recursive", "true"), ("includeItemTypes", "Series")];
+        let resp: ItemResponse = self
+            .client
+            .http
+            .send(|c| c.get(url.clone()).query(&query))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp
+            .items
+            .into_iter()
+            .map(|item| (item.name, item.id))
+            .collect())
+    }
+
+    /// Retrieve the seasons of a single series.
+    pub async fn series_seasons(&self, series_id: &str) -> Result<Vec<Season>> {
+        let url = self.client.build_url(["/Users", &self.user_id, "Items"]);
+        let query = [
+            ("ParentId", series_id),
+            ("includeItemTypes", "Season"),
+            ("Fields", "ChildCount"),
+        ];
+        let resp: SeasonResponse = self
             .client
-            .get(url)
-            .query(&[("Recursive", "true"), ("includeItemTypes", "Season")])
-            .send()?
+            .http
+            .send(|c| c.get(url.clone()).query(&query))
+            .await?
             .error_for_status()?
-            .json()?;
+            .json()
+            .await?;
         Ok(resp.items)
     }
+
+    /// Asks the server to scan the series with the given name for
+    /// changed files.
+    pub async fn refresh_series(&self, name: &str) -> Result<()> {
+        let url = self.client.build_url(["/Users", &self.user_id, "Items"]);
+        let query = [
+            ("Recursive", "true"),
+            ("includeItemTypes", "Series"),
+            ("searchTerm", name),
+        ];
+        let resp: ItemResponse = self
+            .client
+            .http
+            .send(|c| c.get(url.clone()).query(&query))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let series: Vec<Item> = resp.items.into_iter().filter(|i| i.name == name).collect();
+        if series.is_empty() {
+            return Err(anyhow!("no series named {:?}", name));
+        }
+        let query = [
+            ("metadataRefreshMode", "Default"),
+            ("imageRefreshMode", "Default"),
+            ("replaceAllMetadata", "false"),
+            ("replaceAllImages", "false"),
+        ];
+        for item in series.iter() {
+            let url = self.client.build_url(["/Items", &item.id, "Refresh"]);
+            self.client
+                .http
+                .send(|c| c.post(url.clone()).query(&query))
+                .await?
+                .error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+/// An event sent by the Jellyfin webhook plugin. The plugin's
+/// template must produce (at least) these fields.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WebhookEvent {
+    /// The kind of event, e.g. `PlaybackStop`.
+    pub notification_type: String,
+
+    /// Name of the user who caused the event.
+    #[serde(default)]
+    pub notification_username: String,
+
+    /// Kind of item, e.g. `Episode`.
+    #[serde(default)]
+    pub item_type: String,
+
+    /// For episodes, the name of the series.
+    #[serde(default)]
+    pub series_name: String,
+
+    /// For episodes, the item ID of the series.
+    #[serde(default)]
+    pub series_id: String,
+
+    /// Whether playback reached the end of the item.
+    #[serde(default)]
+    pub played_to_completion: bool,
+}
+
+impl WebhookEvent {
+    /// Returns the series ID and name if the event says that `user`
+    /// finished watching an episode.
+    pub fn watched_series(&self, user: &str) -> Option<(&str, &str)> {
+        if self.notification_type == "PlaybackStop"
+            && self.item_type == "Episode"
+            && self.played_to_completion
+            && self.notification_username == user
+        {
+            Some((&self.series_id, &self.series_name))
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
@@ -52,6 +194,20 @@ struct SeasonResponse {
     items: Vec<Season>,
 }
 
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ItemResponse {
+    items: Vec<Item>,
+}
+
+/// An item in the Jellyfin library, e.g. a series.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Item {
+    name: String,
+    id: String,
+}
+
 /// A season of TV shows in Jellyfin.
 #[derive(Debug, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -63,6 +219,10 @@ pub struct Season {
     pub series_name: String,
     id: String,
     user_data: SeasonUserData,
+
+    /// Number of episodes in the season, if the server reported it.
+    #[serde(default)]
+    child_count: Option<u32>,
 }
 
 impl Season {
@@ -70,6 +230,16 @@ impl Season {
     pub fn fully_watched(&self) -> bool {
         self.user_data.unplayed_item_count == 0
     }
+
+    /// Returns how many of the season's episodes the user has watched.
+    pub fn watch_state(&self) -> WatchState {
+        let unplayed = self.user_data.unplayed_item_count as u32;
+        let episodes = self.child_count.unwrap_or(unplayed).max(unplayed);
+        WatchState {
+            episodes,
+            viewed_episodes: episodes - unplayed,
+        }
+    }
 }
 
 /// User-specific data for a season of TV in Jellyfin.
@@ -82,25 +252,49 @@ pub struct SeasonUserData {
 #[derive(Debug)]
 struct BaseClient {
     base_url: reqwest::Url,
-    client: reqwest::Client,
+    http: HttpClient,
 }
 
 impl BaseClient {
+    fn from_config(conf: &config::ServerSettings<config::Jellyfin>) -> Result<BaseClient> {
+        let (base_url, auth_headers) = conf.jellyfin_base()?;
+        let http = HttpClient::new("jellyfin", conf, auth_headers)?;
+        Ok(BaseClient { base_url, http })
+    }
+
     fn build_url<S: AsRef<Path>>(&self, path_bits: impl IntoIterator<Item = S>) -> reqwest::Url {
         let mut path = PathBuf::new();
         for bit in path_bits {
             path.push(bit);
         }
-        self.base_url
-            .join(path.to_str().unwrap_or(""))
-            .expect("hoped for a valid URL")
+        join_url(&self.base_url, path.to_str().unwrap_or("")).expect("hoped for a valid URL")
     }
 
-    /// Retrieve a user ID corresponding to a user name.
-    fn get_user_id(&self, name: &str) -> Result<String> {
+    /// Retrieve information about the server.
+    async fn system_info(&self) -> Result<SystemInfo> {
+        let url = self.build_url(["/System", "Info"]);
+        let resp = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        Ok(resp.json().await?)
+    }
+
+    /// Retrieve all users on the server.
+    async fn users(&self) -> Result<Vec<User>> {
         let url = self.build_url(["/Users"]);
-        let mut resp = self.client.get(url).send()?.error_for_status()?;
-        let users: Vec<User> = resp.json()?;
+        let resp = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        Ok(resp.json().await?)
+    }
+
+    /// Retrieve a user ID corresponding to a user name.
+    async fn get_user_id(&self, name: &str) -> Result<String> {
+        let users = self.users().await?;
         users
             .iter()
             .find(|user| user.name == name)
@@ -116,3 +310,14 @@ pub struct User {
     name: String,
     id: String,
 }
+
+/// A JellyFin API response to the /System/Info route
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SystemInfo {
+    /// Name of the server.
+    pub server_name: String,
+
+    /// Version of the server.
+    pub version: String,
+}
@@ -1,18 +1,33 @@
 //! The Plex Media Server API.
 
+use futures::stream::{self, StreamExt, TryStreamExt};
 use reqwest;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use serde_xml_rs;
 use std::error::Error;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
 
 use crate::config;
+use crate::services::http::{join_url, HttpClient};
+
+/// How often `refresh_show` checks whether a scan is done.
+const REFRESH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long `refresh_show` waits for a scan before giving up on
+/// emptying the trash.
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How many listings `all_tv_seasons` requests at the same time.
+/// The server's `http` settings can limit this further.
+const SCAN_CONCURRENCY: usize = 8;
 
 /// Makes requests to a Plex media server API.
 pub struct PlexClient {
     base_url: reqwest::Url,
-    client: reqwest::Client,
+    http: HttpClient,
 }
 
 /// The kind of media in a plex media server library.
@@ -31,7 +46,7 @@ pub enum MediaKind {
     TVSeason,
 
     /// A single TV show's season's episode.
-    #[serde(rename = "season")]
+    #[serde(rename = "episode")]
     TVEpisode,
 
     /// The pseudo-season entry "All Episodes".
@@ -55,6 +70,45 @@ pub struct Directory {
 
     /// Name assigned in the UI.
     pub title: String,
+
+    /// Whether Plex is scanning the library section right now.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub refreshing: bool,
+
+    /// Folders that the library section's media are in.
+    #[serde(rename = "Location", default)]
+    pub locations: Vec<Location>,
+}
+
+/// Reads a flag attribute, which Plex writes as `"1"` or `"0"`.
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let flag = String::deserialize(deserializer)?;
+    Ok(flag == "1" || flag == "true")
+}
+
+impl Directory {
+    /// True if `path` is in one of the library section's folders.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.locations.iter().any(|l| path.starts_with(&l.path))
+    }
+}
+
+/// A folder that a library section's media are in.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Location {
+    /// Path of the folder on the Plex server.
+    pub path: PathBuf,
+}
+
+/// The identity of a Plex server.
+#[derive(Debug, Deserialize)]
+pub struct Identity {
+    /// Unique ID of the server.
+    #[serde(rename = "machineIdentifier")]
+    pub machine_identifier: String,
+
+    /// Version of the Plex media server.
+    pub version: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -76,6 +130,10 @@ pub struct Show {
 
     /// Name of the show.
     pub title: String,
+
+    /// Rating key of the show, as taken by [`PlexClient::show_seasons`].
+    #[serde(rename = "ratingKey", default)]
+    pub rating_key: String,
 }
 
 fn all_episodes_pseudoseason() -> MediaKind {
@@ -117,6 +175,47 @@ impl Season {
     }
 }
 
+/// An event that Plex sends to its webhooks.
+#[derive(Debug, Deserialize)]
+pub struct WebhookEvent {
+    /// The kind of event, e.g. `media.scrobble`.
+    pub event: String,
+
+    /// The item that the event is about.
+    #[serde(rename = "Metadata")]
+    pub metadata: Option<WebhookMetadata>,
+}
+
+/// The item that a Plex webhook event is about.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookMetadata {
+    /// Kind of item, e.g. `episode`.
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// For episodes, the name of the show.
+    #[serde(default)]
+    pub grandparent_title: String,
+
+    /// For episodes, the rating key of the show.
+    #[serde(default)]
+    pub grandparent_rating_key: String,
+}
+
+impl WebhookEvent {
+    /// Returns the show's rating key and title if the event says
+    /// that an episode was watched.
+    pub fn watched_show(&self) -> Option<(&str, &str)> {
+        match &self.metadata {
+            Some(m) if self.event == "media.scrobble" && m.kind == "episode" => {
+                Some((&m.grandparent_rating_key, &m.grandparent_title))
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct TVListing {
     #[serde(rename = "Directory", default)]
@@ -134,12 +233,9 @@ impl PlexClient {
     pub fn from_config(
         conf: &config::ServerSettings<config::Plex>,
     ) -> Result<PlexClient, Box<dyn Error>> {
-        let (base_url, auth_headers) = conf.plex_base();
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::RedirectPolicy::none())
-            .default_headers(auth_headers)
-            .build()?;
-        Ok(PlexClient { base_url, client })
+        let (base_url, auth_headers) = conf.plex_base()?;
+        let http = HttpClient::new("plex", conf, auth_headers)?;
+        Ok(PlexClient { base_url, http })
     }
 
     fn build_url<S: AsRef<Path>>(&self, path_bits: Vec<S>) -> reqwest::Url {
@@ -147,47 +243,195 @@ impl PlexClient {
         for bit in path_bits {
             path.push(bit);
         }
-        self.base_url
-            .join(path.to_str().unwrap_or(""))
-            .expect("hoped for a valid URL")
+        join_url(&self.base_url, path.to_str().unwrap_or("")).expect("hoped for a valid URL")
+    }
+
+    /// Returns the identity of the server. Plex answers this without
+    /// checking the API key.
+    pub async fn identity(&self) -> Result<Identity, Box<dyn Error>> {
+        let url = self.build_url(vec!["identity"]);
+        let resp = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        Ok(serde_xml_rs::from_str(&resp.text().await?)?)
     }
 
     /// Lists all libraries known to the plex server.
-    fn libraries(&self) -> Result<Vec<Directory>, Box<dyn Error>> {
+    pub async fn libraries(&self) -> Result<Vec<Directory>, Box<dyn Error>> {
         let url = self.build_url(vec!["library/sections"]);
 
-        let resp = self.client.get(url).send()?.error_for_status()?;
-        let container: LibraryOverview = serde_xml_rs::from_reader(resp)?;
+        let resp = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        let container: LibraryOverview = serde_xml_rs::from_str(&resp.text().await?)?;
         Ok(container.directories)
     }
 
-    /// Lists all TV shows in a directory.
-    fn list_shows(&self, library: Directory) -> Result<Vec<Show>, Box<dyn Error>> {
-        let url = self.build_url(vec!["library", "sections", &library.id.to_string(), "all"]);
+    /// Lists all TV shows in a library section.
+    async fn list_shows(&self, section: u32) -> Result<Vec<Show>, Box<dyn Error>> {
+        let url = self.build_url(vec!["library", "sections", &section.to_string(), "all"]);
 
-        let resp = self.client.get(url).send()?.error_for_status()?;
-        let container: TVListing = serde_xml_rs::from_reader(resp)?;
+        let resp = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        let container: TVListing = serde_xml_rs::from_str(&resp.text().await?)?;
         Ok(container.shows)
     }
 
     /// Lists all seasons in a TV show.
-    fn list_seasons(&self, show: Show) -> Result<Vec<Season>, Box<dyn Error>> {
+    async fn list_seasons(&self, show: Show) -> Result<Vec<Season>, Box<dyn Error>> {
         let url = self.build_url(vec![show.id]);
-        let resp = self.client.get(url).send()?.error_for_status()?;
-        let container: TVShow = serde_xml_rs::from_reader(resp)?;
+        let resp = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        let container: TVShow = serde_xml_rs::from_str(&resp.text().await?)?;
         Ok(container.seasons)
     }
 
+    /// Lists all seasons of the TV show with the given rating key.
+    pub async fn show_seasons(&self, show_key: &str) -> Result<Vec<Season>, Box<dyn Error>> {
+        let show = Show {
+            id: format!("/library/metadata/{}/children", show_key),
+            kind: MediaKind::TV,
+            title: String::new(),
+            rating_key: show_key.to_string(),
+        };
+        Ok(self
+            .list_seasons(show)
+            .await?
+            .into_iter()
+            .filter(|s| s.kind != MediaKind::AllEpisodes)
+            .collect())
+    }
+
+    /// Returns all TV shows (in all TV libraries) known to Plex.
+    pub async fn all_shows(&self) -> Result<Vec<Show>, Box<dyn Error>> {
+        let libraries = self
+            .libraries()
+            .await?
+            .into_iter()
+            .filter(|d| d.kind == MediaKind::TV);
+        stream::iter(libraries)
+            .map(|l| self.list_shows(l.id))
+            .buffered(SCAN_CONCURRENCY)
+            .try_concat()
+            .await
+    }
+
     /// Returns a list of all TV show seasons (in all TV libraries)
     /// known to Plex.
-    pub fn all_tv_seasons(&self) -> Result<Vec<Season>, Box<dyn Error>> {
-        Ok(self
-            .libraries()?
+    pub async fn all_tv_seasons(&self) -> Result<Vec<Season>, Box<dyn Error>> {
+        let shows = self.all_shows().await?;
+        let seasons: Vec<Season> = stream::iter(shows)
+            .map(|s| self.list_seasons(s))
+            .buffered(SCAN_CONCURRENCY)
+            .try_concat()
+            .await?;
+        Ok(seasons
             .into_iter()
-            .filter(|d| d.kind == MediaKind::TV)
-            .flat_map(|l| self.list_shows(l).expect("could not list library"))
-            .flat_map(|s| self.list_seasons(s).expect("could not list show"))
             .filter(|s| s.kind != MediaKind::AllEpisodes)
             .collect())
     }
+
+    /// Asks Plex to scan the show with the given title for changes,
+    /// in every TV library section that has it. If `path` (where
+    /// Sonarr keeps the show) is in a section's folders, only that
+    /// path is scanned. With `empty_trash`, waits for the scans to
+    /// finish and then empties the sections' trash.
+    pub async fn refresh_show(
+        &self,
+        title: &str,
+        path: Option<&Path>,
+        empty_trash: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut sections = vec![];
+        for library in self.libraries().await? {
+            if library.kind != MediaKind::TV {
+                continue;
+            }
+            let shows = self.list_shows(library.id).await?;
+            if shows.iter().any(|show| show.title == title) {
+                sections.push(library);
+            }
+        }
+        if sections.is_empty() {
+            return Err(format!("no show named {:?}", title).into());
+        }
+        for section in sections.iter() {
+            let path = path
+                .filter(|p| section.contains(p))
+                .and_then(|p| p.to_str());
+            self.refresh_section(section.id, path).await?;
+        }
+        if empty_trash {
+            for section in sections.iter() {
+                self.wait_for_refresh(section.id).await?;
+                self.empty_trash(section.id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a scan of a library section, or only of `path` in it.
+    async fn refresh_section(
+        &self,
+        section: u32,
+        path: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        let url = self.build_url(vec!["library", "sections", &section.to_string(), "refresh"]);
+        let query: Vec<(&str, &str)> = path.map(|p| ("path", p)).into_iter().collect();
+        self.http
+            .send(|c| c.get(url.clone()).query(&query))
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Waits until Plex isn't scanning a library section anymore.
+    async fn wait_for_refresh(&self, section: u32) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        loop {
+            // give the scan a moment to start:
+            sleep(REFRESH_POLL_INTERVAL).await;
+            let refreshing = self
+                .libraries()
+                .await?
+                .iter()
+                .any(|l| l.id == section && l.refreshing);
+            if !refreshing {
+                return Ok(());
+            }
+            if start.elapsed() > REFRESH_TIMEOUT {
+                return Err(format!(
+                    "library section {} is still being scanned after {:?}",
+                    section, REFRESH_TIMEOUT
+                )
+                .into());
+            }
+        }
+    }
+
+    /// Removes the entries of media whose files are gone from a
+    /// library section.
+    async fn empty_trash(&self, section: u32) -> Result<(), Box<dyn Error>> {
+        let url = self.build_url(vec![
+            "library",
+            "sections",
+            &section.to_string(),
+            "emptyTrash",
+        ]);
+        self.http
+            .send(|c| c.put(url.clone()))
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 }
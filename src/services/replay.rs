@@ -0,0 +1,550 @@
+//! Recording API responses into fixture files, and replaying them.
+//!
+//! With `$SPC_RECORD_DIR` set, every response from Sonarr, Plex and
+//! Jellyfin is also written to `<service>.json` in that directory.
+//! With `$SPC_REPLAY_DIR` set, no request reaches the network: the
+//! recorded responses are returned instead, so that tests can run
+//! offline.
+//!
+//! Recorded URLs are relative to the server's URL. API keys and other
+//! credentials, the server's host name, file paths, server identities
+//! and user names and IDs are redacted from the recordings.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::{Method, Response, Url};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::ServerSettings;
+use crate::prelude::*;
+use crate::services::http::join_url;
+
+/// Environment variable naming the directory to record responses in.
+pub const RECORD_DIR_VAR: &str = "SPC_RECORD_DIR";
+
+/// Environment variable naming the directory to replay responses from.
+pub const REPLAY_DIR_VAR: &str = "SPC_REPLAY_DIR";
+
+/// What redacted values are replaced with.
+const REDACTED: &str = "REDACTED";
+
+/// What the server's host name is replaced with.
+const REDACTED_HOST: &str = "server.example";
+
+/// JSON fields whose values are redacted.
+const REDACTED_FIELDS: &[&str] = &[
+    "path",
+    "relativePath",
+    "originalFilePath",
+    "rootFolderPath",
+    "folder",
+    "sceneName",
+    "Path",
+    "ServerName",
+    "LocalAddress",
+    "LocalAddresses",
+    "WanAddress",
+];
+
+/// XML attributes whose values are redacted.
+const REDACTED_ATTRIBUTES: &[&str] = &["path", "machineIdentifier", "friendlyName"];
+
+/// A request and the response that the server sent to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    /// HTTP method of the request.
+    pub method: String,
+
+    /// URL of the request, relative to the server's URL.
+    pub path: String,
+
+    /// Status code of the response.
+    pub status: u16,
+
+    /// Content type of the response, if the server sent one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+
+    /// Body of the response: the parsed JSON document for JSON
+    /// responses, a string for anything else.
+    pub body: Value,
+}
+
+impl Exchange {
+    fn is_json(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .unwrap_or_default()
+            .contains("json")
+    }
+
+    /// Returns a response with the recorded status and body.
+    fn response(&self) -> Response {
+        let body = match &self.body {
+            Value::String(text) if !self.is_json() => text.clone(),
+            body => body.to_string(),
+        };
+        let mut builder = http::Response::builder().status(self.status);
+        if let Some(content_type) = &self.content_type {
+            builder = builder.header(CONTENT_TYPE, content_type.as_str());
+        }
+        builder
+            .body(body)
+            .expect("recorded response is valid")
+            .into()
+    }
+}
+
+/// Returns `url` relative to the server URL `base`.
+fn relative(base: &Url, url: &Url) -> String {
+    let url = url.as_str();
+    url.strip_prefix(base.as_str()).unwrap_or(url).to_string()
+}
+
+fn fixture_path(dir: &Path, service: &str) -> PathBuf {
+    dir.join(format!("{}.json", service))
+}
+
+/// What the ID of the `number`th Jellyfin user is replaced with.
+fn redacted_user_id(number: usize) -> String {
+    format!("user{}-id", number)
+}
+
+/// Replaces the values of [`REDACTED_FIELDS`], and the names and IDs
+/// of Jellyfin users (objects with a `HasPassword` field) with
+/// `user1` and `user1-id`, `user2` and `user2-id` and so on. `user_ids`
+/// holds the IDs of the users seen so far, so that each user keeps
+/// their number.
+fn redact_json(value: &mut Value, user_ids: &mut Vec<String>) {
+    match value {
+        Value::Object(object) => {
+            if object.contains_key("HasPassword") {
+                let id = object
+                    .get("Id")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                let number = match user_ids.iter().position(|known| *known == id) {
+                    Some(i) => i + 1,
+                    None => {
+                        user_ids.push(id);
+                        user_ids.len()
+                    }
+                };
+                if let Some(name) = object.get_mut("Name") {
+                    *name = Value::String(format!("user{}", number));
+                }
+                if let Some(id) = object.get_mut("Id") {
+                    *id = Value::String(redacted_user_id(number));
+                }
+            }
+            for (key, value) in object.iter_mut() {
+                if REDACTED_FIELDS.contains(&key.as_str()) {
+                    redact_strings(value);
+                } else {
+                    redact_json(value, user_ids);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                redact_json(value, user_ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_strings(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = REDACTED.to_string(),
+        Value::Array(values) => values.iter_mut().for_each(redact_strings),
+        _ => {}
+    }
+}
+
+/// Replaces the values of [`REDACTED_ATTRIBUTES`] in an XML document.
+fn redact_xml(body: &str) -> String {
+    let mut body = body.to_string();
+    for attribute in REDACTED_ATTRIBUTES {
+        let pattern = format!(" {}=\"", attribute);
+        let mut redacted = String::with_capacity(body.len());
+        let mut rest = body.as_str();
+        while let Some(start) = rest.find(&pattern) {
+            let value_start = start + pattern.len();
+            let value_end = match rest[value_start..].find('"') {
+                Some(end) => value_start + end,
+                None => break,
+            };
+            redacted.push_str(&rest[..value_start]);
+            redacted.push_str(REDACTED);
+            rest = &rest[value_end..];
+        }
+        redacted.push_str(rest);
+        body = redacted;
+    }
+    body
+}
+
+/// Writes the responses from one server to a fixture file.
+pub struct Recorder {
+    path: PathBuf,
+    base: Url,
+    host: Option<String>,
+    secrets: Vec<String>,
+    user_ids: Mutex<Vec<String>>,
+    exchanges: Mutex<Vec<Exchange>>,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Recorder {
+    fn new<T>(path: PathBuf, conf: &ServerSettings<T>, headers: &HeaderMap) -> Recorder {
+        let mut secrets: Vec<String> = headers
+            .values()
+            .filter_map(|value| value.to_str().ok())
+            .map(String::from)
+            .collect();
+        if let Some(password) = conf.basic_auth.as_ref().and_then(|a| a.password.as_ref()) {
+            secrets.push(password.expose_secret().as_str().to_string());
+        }
+        for value in conf.headers.values() {
+            secrets.push(value.expose_secret().as_str().to_string());
+        }
+        secrets.retain(|s| !s.is_empty());
+        Recorder {
+            path,
+            base: join_url(&conf.url, "").unwrap_or_else(|_| conf.url.clone()),
+            host: conf.url.host_str().map(String::from),
+            secrets,
+            user_ids: Mutex::new(vec![]),
+            exchanges: Mutex::new(vec![]),
+        }
+    }
+
+    /// Replaces the secrets, the server's host name and the IDs of the
+    /// Jellyfin users seen so far in `text`, e.g. in
+    /// `Users/<id>/Items`.
+    fn redact_text(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for secret in self.secrets.iter() {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        for (i, id) in self.user_ids.lock().unwrap().iter().enumerate() {
+            if !id.is_empty() {
+                text = text.replace(id.as_str(), &redacted_user_id(i + 1));
+            }
+        }
+        if let Some(host) = &self.host {
+            text = text.replace(host.as_str(), REDACTED_HOST);
+        }
+        text
+    }
+
+    /// Records the response to a request, and returns an equivalent
+    /// response to pass on.
+    pub async fn record(
+        &self,
+        method: &Method,
+        url: &Url,
+        response: Response,
+    ) -> reqwest::Result<Response> {
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let text = response.text().await?;
+        let original = Exchange {
+            method: method.to_string(),
+            path: relative(&self.base, url),
+            status,
+            content_type,
+            body: Value::String(text),
+        };
+
+        let text = original.body.as_str().unwrap_or_default();
+        let body = match serde_json::from_str::<Value>(text) {
+            Ok(mut value) if original.is_json() => {
+                // Users are redacted first, so that their IDs get
+                // redacted everywhere else from now on.
+                redact_json(&mut value, &mut self.user_ids.lock().unwrap());
+                let redacted_text = self.redact_text(&value.to_string());
+                serde_json::from_str(&redacted_text).unwrap_or(Value::String(redacted_text))
+            }
+            _ => Value::String(redact_xml(&self.redact_text(text))),
+        };
+        let exchange = Exchange {
+            path: self.redact_text(&original.path),
+            body,
+            ..original.clone()
+        };
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        exchanges.push(exchange);
+        let written = serde_json::to_string_pretty(&*exchanges)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(fs::write(&self.path, json)?));
+        if let Err(e) = written {
+            warn!("Could not record to {}: {}", self.path.display(), e);
+        }
+        Ok(original.response())
+    }
+}
+
+/// Answers requests with the responses from a fixture file.
+#[derive(Debug)]
+pub struct Replayer {
+    path: PathBuf,
+    base: Url,
+    exchanges: Mutex<Vec<(Exchange, bool)>>,
+}
+
+impl Replayer {
+    fn load(path: PathBuf, base: &Url) -> anyhow::Result<Replayer> {
+        let contents =
+            fs::read_to_string(&path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let exchanges: Vec<Exchange> =
+            serde_json::from_str(&contents).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(Replayer {
+            path,
+            base: join_url(base, "")?,
+            exchanges: Mutex::new(exchanges.into_iter().map(|e| (e, false)).collect()),
+        })
+    }
+
+    /// Returns the recorded response to a request. If the same
+    /// request was recorded several times, the responses are returned
+    /// in order, and the last one repeats. Requests that weren't
+    /// recorded get a 501 response.
+    pub fn replay(&self, method: &Method, url: &Url) -> Response {
+        let path = relative(&self.base, url);
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let matching: Vec<usize> = exchanges
+            .iter()
+            .enumerate()
+            .filter(|(_, (e, _))| e.method == method.as_str() && e.path == path)
+            .map(|(i, _)| i)
+            .collect();
+        let chosen = matching
+            .iter()
+            .find(|i| !exchanges[**i].1)
+            .or_else(|| matching.last())
+            .copied();
+        match chosen {
+            Some(i) => {
+                exchanges[i].1 = true;
+                exchanges[i].0.response()
+            }
+            None => {
+                warn!(
+                    "{}: no recorded response for {} {}",
+                    self.path.display(),
+                    method,
+                    path
+                );
+                http::Response::builder()
+                    .status(501)
+                    .body(format!("no recorded response for {} {}", method, path))
+                    .expect("valid response")
+                    .into()
+            }
+        }
+    }
+}
+
+/// Where an [`HttpClient`](super::http::HttpClient) sends its requests.
+#[derive(Debug, Clone)]
+pub enum Transport {
+    /// To the server.
+    Live,
+
+    /// To the server, recording the responses.
+    Record(Arc<Recorder>),
+
+    /// Nowhere: recorded responses are returned instead.
+    Replay(Arc<Replayer>),
+}
+
+lazy_static! {
+    // All clients for a service share one fixture file.
+    static ref RECORDERS: Mutex<HashMap<PathBuf, Arc<Recorder>>> = Default::default();
+    static ref REPLAYERS: Mutex<HashMap<PathBuf, Arc<Replayer>>> = Default::default();
+}
+
+impl Transport {
+    /// Returns the transport that `$SPC_RECORD_DIR` or
+    /// `$SPC_REPLAY_DIR` select for `service`. The values of `headers`
+    /// (which hold the API key) are redacted from recordings.
+    pub fn from_env<T>(
+        service: &str,
+        conf: &ServerSettings<T>,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<Transport> {
+        match (env::var_os(RECORD_DIR_VAR), env::var_os(REPLAY_DIR_VAR)) {
+            (Some(_), Some(_)) => Err(anyhow!(
+                "only one of ${} and ${} may be set",
+                RECORD_DIR_VAR,
+                REPLAY_DIR_VAR
+            )),
+            (Some(dir), None) => {
+                fs::create_dir_all(&dir)?;
+                let path = fixture_path(Path::new(&dir), service);
+                let mut recorders = RECORDERS.lock().unwrap();
+                let recorder = recorders
+                    .entry(path.clone())
+                    .or_insert_with(|| Arc::new(Recorder::new(path, conf, headers)));
+                Ok(Transport::Record(Arc::clone(recorder)))
+            }
+            (None, Some(dir)) => {
+                let path = fixture_path(Path::new(&dir), service);
+                let mut replayers = REPLAYERS.lock().unwrap();
+                if let Some(replayer) = replayers.get(&path) {
+                    return Ok(Transport::Replay(Arc::clone(replayer)));
+                }
+                let replayer = Arc::new(Replayer::load(path.clone(), &conf.url)?);
+                replayers.insert(path, Arc::clone(&replayer));
+                Ok(Transport::Replay(replayer))
+            }
+            (None, None) => Ok(Transport::Live),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use serde_json::json;
+
+    fn recorder(path: PathBuf) -> Recorder {
+        let mut conf: ServerSettings<()> = ServerSettings::new(
+            Url::parse("http://jellyfin.lan:8096/jf/").unwrap(),
+            "api-key".to_string(),
+        );
+        conf.headers.insert(
+            "X-Proxy-Token".to_string(),
+            secrecy::Secret::new(serde_json::from_value(json!("proxy-token")).unwrap()),
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("x-emby-token", HeaderValue::from_static("api-key"));
+        Recorder::new(path, &conf, &headers)
+    }
+
+    fn response(body: &str) -> Response {
+        http::Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn redact_json_replaces_fields_and_users() {
+        let mut user_ids = vec![];
+        let mut value = json!({
+            "Path": "/media/tv/Show",
+            "LocalAddresses": ["10.0.0.2", "fe80::1"],
+            "Items": [{"Name": "Show", "Id": "show-1"}],
+            "Users": [
+                {"Name": "alice", "Id": "a1", "HasPassword": true},
+                {"Name": "bob", "Id": "b2", "HasPassword": false},
+            ],
+        });
+        redact_json(&mut value, &mut user_ids);
+        assert_eq!(
+            value,
+            json!({
+                "Path": REDACTED,
+                "LocalAddresses": [REDACTED, REDACTED],
+                "Items": [{"Name": "Show", "Id": "show-1"}],
+                "Users": [
+                    {"Name": "user1", "Id": "user1-id", "HasPassword": true},
+                    {"Name": "user2", "Id": "user2-id", "HasPassword": false},
+                ],
+            })
+        );
+        assert_eq!(user_ids, vec!["a1", "b2"]);
+
+        // The same users keep their numbers in later responses.
+        let mut value = json!([{"Name": "bob", "Id": "b2", "HasPassword": true}]);
+        redact_json(&mut value, &mut user_ids);
+        assert_eq!(
+            value,
+            json!([{"Name": "user2", "Id": "user2-id", "HasPassword": true}])
+        );
+    }
+
+    #[test]
+    fn redact_xml_replaces_attributes() {
+        let xml = r#"<MediaContainer machineIdentifier="abc123" friendlyName="den"><Location id="1" path="/tv"/></MediaContainer>"#;
+        assert_eq!(
+            redact_xml(xml),
+            r#"<MediaContainer machineIdentifier="REDACTED" friendlyName="REDACTED"><Location id="1" path="REDACTED"/></MediaContainer>"#
+        );
+    }
+
+    #[test]
+    fn redact_text_replaces_secrets_and_host() {
+        let recorder = recorder(PathBuf::from("unused.json"));
+        assert_eq!(
+            recorder
+                .redact_text("http://jellyfin.lan:8096/jf/Items?api_key=api-key&token=proxy-token"),
+            "http://server.example:8096/jf/Items?api_key=REDACTED&token=REDACTED"
+        );
+    }
+
+    #[test]
+    fn record_redacts_user_ids_in_paths_and_bodies() {
+        let dir = env::temp_dir().join(format!("spc-replay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("jellyfin.json");
+        let recorder = recorder(path.clone());
+        let base = Url::parse("http://jellyfin.lan:8096/jf/").unwrap();
+        let users = r#"[{"Name": "alice", "Id": "0f3a9c", "HasPassword": true}]"#;
+        let items = r#"{"Items": [{"Name": "Season 1", "UserId": "0f3a9c"}]}"#;
+
+        crate::runtime::block_on(async {
+            let url = base.join("Users").unwrap();
+            recorder
+                .record(&Method::GET, &url, response(users))
+                .await
+                .unwrap();
+            let url = base.join("Users/0f3a9c/Items?api_key=api-key").unwrap();
+            let passed_on = recorder
+                .record(&Method::GET, &url, response(items))
+                .await
+                .unwrap();
+            // The caller still gets the original response.
+            assert!(passed_on.text().await.unwrap().contains("0f3a9c"));
+        });
+
+        let recorded = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!recorded.contains("0f3a9c"), "{}", recorded);
+        assert!(!recorded.contains("alice"), "{}", recorded);
+        let exchanges: Vec<Exchange> = serde_json::from_str(&recorded).unwrap();
+        assert_eq!(exchanges[0].path, "Users");
+        assert_eq!(exchanges[0].body[0]["Id"], "user1-id");
+        assert_eq!(exchanges[1].path, "Users/user1-id/Items?api_key=REDACTED");
+        assert_eq!(exchanges[1].body["Items"][0]["UserId"], "user1-id");
+    }
+}
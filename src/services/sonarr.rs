@@ -3,15 +3,17 @@
 use crate::prelude::*;
 
 use chrono::{DateTime, Utc};
-use reqwest;
-use retry::{delay::Exponential, retry};
+use reqwest::{StatusCode, Url};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::path::PathBuf;
+use tokio::sync::OnceCell;
 
 use crate::config;
+use crate::services::http::{join_url, HttpClient};
 
 /// Statistics about a season known to sonarr (via the TV db).
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Hash)]
@@ -76,6 +78,10 @@ pub struct Series {
 
     /// Seasons known to Sonarr (via the TV metadata DB).
     pub seasons: Vec<Season>,
+
+    /// Folder that the series' files are in.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
 }
 
 impl IdEd for Series {
@@ -134,10 +140,152 @@ pub struct EpisodeFile {
     pub size: u128,
 }
 
+/// An event sent by Sonarr's "Webhook" connection.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookEvent {
+    /// The kind of event, e.g. `Download` or `SeriesDelete`.
+    pub event_type: String,
+
+    /// The series that the event is about.
+    pub series: Option<WebhookSeries>,
+
+    /// The episodes that the event is about.
+    #[serde(default)]
+    pub episodes: Vec<WebhookEpisode>,
+
+    /// The file that was imported or deleted.
+    pub episode_file: Option<WebhookEpisodeFile>,
+}
+
+impl WebhookEvent {
+    /// Returns the (deduplicated) season numbers of the event's episodes.
+    pub fn season_numbers(&self) -> Vec<u32> {
+        let mut seasons: Vec<u32> = self.episodes.iter().map(|e| e.season_number).collect();
+        seasons.sort_unstable();
+        seasons.dedup();
+        seasons
+    }
+}
+
+/// A series, as described in a Sonarr webhook event.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookSeries {
+    /// Sonarr API object ID.
+    pub id: u32,
+
+    /// Title of the series.
+    pub title: String,
+}
+
+/// An episode, as described in a Sonarr webhook event.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookEpisode {
+    /// 1-based index of the season.
+    pub season_number: u32,
+
+    /// 1-based number of the episode.
+    pub episode_number: u32,
+}
+
+/// An episode file, as described in a Sonarr webhook event.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookEpisodeFile {
+    /// API object ID.
+    pub id: u32,
+
+    /// Number of bytes that this file occupies.
+    #[serde(default)]
+    pub size: u128,
+}
+
+/// Status information about the Sonarr server.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemStatus {
+    /// Version of Sonarr.
+    pub version: String,
+}
+
+impl SystemStatus {
+    /// Returns the major version of Sonarr, or 0 if the version
+    /// can't be parsed.
+    pub fn major_version(&self) -> u32 {
+        self.version
+            .split('.')
+            .next()
+            .and_then(|major| major.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// The flavour of API that a Sonarr server speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// The API under `/api/` that Sonarr v2 serves.
+    Legacy,
+
+    /// The API under `/api/v3/` that Sonarr v3 and v4 serve.
+    V3,
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiVersion::Legacy => write!(f, "legacy API"),
+            ApiVersion::V3 => write!(f, "v3 API"),
+        }
+    }
+}
+
+/// What became of the files of a season that were to be deleted.
+#[derive(Debug)]
+pub enum SeasonDeletion {
+    /// All of the files were deleted.
+    Complete,
+
+    /// None of the files were deleted.
+    Failed(Box<dyn Error>),
+
+    /// Some of the files were deleted before an error stopped the
+    /// deletion.
+    Partial {
+        /// IDs of the files that were deleted.
+        deleted: Vec<u32>,
+
+        /// IDs of the files that are still there.
+        remaining: Vec<u32>,
+
+        /// What went wrong.
+        error: Box<dyn Error>,
+    },
+}
+
 /// Sonarr API client.
+///
+/// The client works out which API the server speaks on its first
+/// request, and uses the v3 API where it is available.
 pub struct SonarrClient {
-    client: reqwest::Client,
-    base_url: reqwest::Url,
+    http: HttpClient,
+    server_url: Url,
+    api: OnceCell<(ApiVersion, Url)>,
+}
+
+/// Returns the URL that Sonarr itself is served at, given a URL that
+/// may point at one of its APIs (`.../api/` or `.../api/v3/`).
+fn server_url(url: &Url) -> Url {
+    let mut segments: Vec<&str> = url.path().split('/').filter(|s| !s.is_empty()).collect();
+    if segments.ends_with(&["api", "v3"]) {
+        segments.truncate(segments.len() - 2);
+    } else if segments.ends_with(&["api"]) {
+        segments.truncate(segments.len() - 1);
+    }
+    let mut url = url.clone();
+    url.set_path(&format!("/{}", segments.join("/")));
+    url
 }
 
 /// A Sonarr tag.
@@ -171,6 +319,19 @@ impl Tags {
     pub fn get(&self, name: &str) -> Option<&Tag> {
         self.tags.iter().find(|t| t.label == name)
     }
+
+    /// Returns the names of all tags.
+    pub fn labels(&self) -> Vec<&str> {
+        self.tags.iter().map(|t| t.label.as_str()).collect()
+    }
+
+    /// Returns the name of the tag with a given ID.
+    pub fn label(&self, id: TagId) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|t| t.id == id)
+            .map(|t| t.label.as_str())
+    }
 }
 
 impl SonarrClient {
@@ -178,67 +339,143 @@ impl SonarrClient {
     pub fn from_config(
         conf: &config::ServerSettings<config::Sonarr>,
     ) -> Result<SonarrClient, Box<dyn Error>> {
-        let (base_url, auth_headers) = conf.sonarr_base();
-        let client = reqwest::Client::builder()
-            .default_headers(auth_headers)
-            .redirect(reqwest::RedirectPolicy::none()) // getting redirected means we're doing it wrong
-            .build()?;
-        Ok(SonarrClient { client, base_url })
+        let (base_url, auth_headers) = conf.sonarr_base()?;
+        let http = HttpClient::new("sonarr", conf, auth_headers)?;
+        Ok(SonarrClient {
+            http,
+            server_url: server_url(&base_url),
+            api: OnceCell::new(),
+        })
+    }
+
+    /// Returns the API that the server speaks, and the URL it is
+    /// served at.
+    async fn api(&self) -> Result<&(ApiVersion, Url), Box<dyn Error>> {
+        self.api.get_or_try_init(|| self.detect_api()).await
+    }
+
+    async fn detect_api(&self) -> Result<(ApiVersion, Url), Box<dyn Error>> {
+        // Only a 404 means that there is no v3 API; any other error
+        // is returned, and detection is tried again on the next call.
+        let url = join_url(&self.server_url, "api/v3/system/status")?;
+        let response = self.http.send(|c| c.get(url.clone())).await?;
+        let status = match response.status() {
+            StatusCode::NOT_FOUND => None,
+            _ => Some(response.error_for_status()?.json::<SystemStatus>().await?),
+        };
+        match status {
+            Some(status) if status.major_version() >= 3 => {
+                debug!("sonarr {}: using the v3 API", status.version);
+                Ok((ApiVersion::V3, join_url(&self.server_url, "api/v3/")?))
+            }
+            _ => {
+                debug!("sonarr: no v3 API, using the legacy API");
+                Ok((ApiVersion::Legacy, join_url(&self.server_url, "api/")?))
+            }
+        }
+    }
+
+    /// Returns the URL of an endpoint of the server's API.
+    async fn api_url(&self, path: &str) -> Result<Url, Box<dyn Error>> {
+        Ok(join_url(&self.api().await?.1, path)?)
+    }
+
+    /// Returns the API that the server speaks.
+    pub async fn api_version(&self) -> Result<ApiVersion, Box<dyn Error>> {
+        Ok(self.api().await?.0)
+    }
+
+    /// Returns the status of the Sonarr server.
+    pub async fn system_status(&self) -> Result<SystemStatus, Box<dyn Error>> {
+        let url = self.api_url("system/status").await?;
+        let response = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
     }
 
     /// Returns all tags known to Sonarr.
-    pub fn fetch_tags(&self) -> Result<Tags, Box<dyn Error>> {
-        let url = self.base_url.join("tag")?;
-        let req = self.client.get(url);
-        let mut response = req.send()?.error_for_status()?;
-        let tags: Vec<Tag> = response.json()?;
+    pub async fn fetch_tags(&self) -> Result<Tags, Box<dyn Error>> {
+        let url = self.api_url("tag").await?;
+        let response = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        let tags: Vec<Tag> = response.json().await?;
         Ok(Tags { tags })
     }
 
     /// Fetches all the TV series that Sonarr knows about.
-    pub fn fetch_all_series(&self) -> Result<Vec<Series>, Box<dyn Error>> {
-        let url = self.base_url.join("series")?;
-        let req = self.client.get(url);
-        let mut response = req.send()?.error_for_status()?;
-        let series: Vec<Series> = response.json()?;
+    pub async fn fetch_all_series(&self) -> Result<Vec<Series>, Box<dyn Error>> {
+        let url = self.api_url("series").await?;
+        let response = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        let series: Vec<Series> = response.json().await?;
         Ok(series)
     }
 
     /// Fetches information about a single series.
-    pub fn fetch_series<S: DeserializeOwned>(&self, series_id: u32) -> Result<S, Box<dyn Error>> {
-        let url = self.base_url.join(
-            PathBuf::from("series")
-                .join(&series_id.to_string())
-                .to_str()
-                .unwrap(),
-        )?;
-        let req = self.client.get(url);
-        let mut response = req.send()?.error_for_status()?;
-        Ok(response.json()?)
+    pub async fn fetch_series<S: DeserializeOwned>(
+        &self,
+        series_id: u32,
+    ) -> Result<S, Box<dyn Error>> {
+        let url = self
+            .api_url(
+                PathBuf::from("series")
+                    .join(series_id.to_string())
+                    .to_str()
+                    .unwrap(),
+            )
+            .await?;
+        let response = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
     }
 
     /// Updates information about a single TV show.
-    fn update_series<S: Serialize + IdEd>(&self, series: &S) -> Result<Series, Box<dyn Error>> {
-        let url = self.base_url.join(
-            PathBuf::from("series")
-                .join(&series.id().to_string())
-                .to_str()
-                .unwrap(),
-        )?;
-        let req = self.client.put(url).json(&series);
-        let mut response = req.send()?.error_for_status()?;
-        Ok(response.json()?)
+    async fn update_series<S: Serialize + IdEd>(
+        &self,
+        series: &S,
+    ) -> Result<Series, Box<dyn Error>> {
+        let url = self
+            .api_url(
+                PathBuf::from("series")
+                    .join(series.id().to_string())
+                    .to_str()
+                    .unwrap(),
+            )
+            .await?;
+        let response = self
+            .http
+            .send(|c| c.put(url.clone()).json(&series))
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
     }
 
     /// Returns all [`EpisodeFile`]s in a TV series.
-    pub fn fetch_episode_files(&self, series_id: u32) -> Result<Vec<EpisodeFile>, Box<dyn Error>> {
+    pub async fn fetch_episode_files(
+        &self,
+        series_id: u32,
+    ) -> Result<Vec<EpisodeFile>, Box<dyn Error>> {
         let url = self
-            .base_url
-            .join(&format!("episodefile?seriesId={}", series_id))?;
-        let req = self.client.get(url);
-
-        let mut response = req.send()?.error_for_status()?;
-        let epfiles: Vec<EpisodeFile> = response.json()?;
+            .api_url(&format!("episodefile?seriesId={}", series_id))
+            .await?;
+        let response = self
+            .http
+            .send(|c| c.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        let epfiles: Vec<EpisodeFile> = response.json().await?;
         Ok(epfiles)
     }
 
@@ -246,7 +483,26 @@ impl SonarrClient {
     ///
     /// This makes Sonarr skip downloading more/updated episodes for
     /// the season.
-    pub fn unmonitor_season(&self, series_id: u32, season: u32) -> Result<(), Box<dyn Error>> {
+    pub async fn unmonitor_season(
+        &self,
+        series_id: u32,
+        season: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.api_version().await? == ApiVersion::V3 {
+            let url = self.api_url("seasonpass").await?;
+            let body = json!({
+                "series": [{
+                    "id": series_id,
+                    "seasons": [{"seasonNumber": season, "monitored": false}],
+                }],
+            });
+            self.http
+                .send(|c| c.post(url.clone()).json(&body))
+                .await?
+                .error_for_status()?;
+            return Ok(());
+        }
+
         #[derive(Deserialize, Serialize, Debug, PartialEq)]
         #[serde(rename_all = "camelCase")]
         struct UpdateSeries {
@@ -271,7 +527,7 @@ impl SonarrClient {
             extra: HashMap<String, Value>,
         }
 
-        let mut series: UpdateSeries = self.fetch_series(series_id)?;
+        let mut series: UpdateSeries = self.fetch_series(series_id).await?;
         if let Some((i, _)) = series
             .seasons
             .iter()
@@ -279,45 +535,155 @@ impl SonarrClient {
             .find(|(_, s)| s.season_number == season)
         {
             series.seasons[i].monitored = false;
-            self.update_series(&series)?;
+            self.update_series(&series).await?;
         }
         Ok(())
     }
 
-    /// Deletes a list of [`EpisodeFile`]s.
-    pub fn delete_episode_file(&self, ef: &EpisodeFile) -> Result<(), Box<dyn Error>> {
-        let url = self.base_url.join(
-            PathBuf::from("episodefile")
-                .join(&ef.id.to_string())
-                .to_str()
-                .unwrap(),
-        )?;
-        let req = self.client.delete(url.clone());
-        match req.send()? {
-            resp if resp.status().is_success() => Ok(()),
-            resp if resp.status().is_server_error() => {
-                // retry on failure and don't worry if the file is gone already:
-                retry(Exponential::from_millis(200), || {
-                    info!(
-                        "HTTP DELETE failed with status {:?}. Retrying...",
-                        resp.status()
-                    );
-                    let req = self.client.delete(url.clone());
-                    match req.send()? {
-                        resp if resp.status().is_success()
-                            || resp.status() == reqwest::StatusCode::NOT_FOUND =>
-                        {
-                            Ok(())
-                        }
-                        resp => resp.error_for_status().map(|_| ()),
-                    }
-                })?;
-                Ok(())
+    /// Adds a tag to a TV series, if it isn't tagged with it already.
+    pub async fn tag_series(&self, series_id: u32, tag: TagId) -> Result<(), Box<dyn Error>> {
+        #[derive(Deserialize, Serialize, Debug, PartialEq)]
+        #[serde(rename_all = "camelCase")]
+        struct UpdateSeries {
+            id: u32,
+            tags: Vec<TagId>,
+            #[serde(flatten)]
+            extra: HashMap<String, Value>,
+        }
+
+        impl IdEd for UpdateSeries {
+            fn id(&self) -> u32 {
+                self.id
             }
+        }
+
+        let mut series: UpdateSeries = self.fetch_series(series_id).await?;
+        if !series.tags.contains(&tag) {
+            series.tags.push(tag);
+            self.update_series(&series).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes an [`EpisodeFile`].
+    pub async fn delete_episode_file(&self, ef: &EpisodeFile) -> Result<(), Box<dyn Error>> {
+        let url = self
+            .api_url(
+                PathBuf::from("episodefile")
+                    .join(ef.id.to_string())
+                    .to_str()
+                    .unwrap(),
+            )
+            .await?;
+        // a retried DELETE may find the file gone already:
+        match self.http.send(|c| c.delete(url.clone())).await? {
+            resp if resp.status() == StatusCode::NOT_FOUND => Ok(()),
             resp => {
-                resp.error_for_status().map(|_| ())?;
+                resp.error_for_status()?;
                 Ok(())
             }
         }
     }
+
+    /// Deletes the [`EpisodeFile`]s of a season in series
+    /// `series_id`: all in one request if the server speaks the v3
+    /// API, one by one if it doesn't.
+    ///
+    /// If that fails, the series' files are listed again to find out
+    /// which of them are gone.
+    pub async fn delete_season_files(
+        &self,
+        series_id: u32,
+        files: &[&EpisodeFile],
+    ) -> SeasonDeletion {
+        if files.is_empty() {
+            return SeasonDeletion::Complete;
+        }
+        let result = match self.api_version().await {
+            Ok(ApiVersion::V3) => self.bulk_delete_episode_files(files).await,
+            Ok(ApiVersion::Legacy) => self.delete_episode_files(files).await,
+            Err(e) => Err(e),
+        };
+        let error = match result {
+            Ok(()) => return SeasonDeletion::Complete,
+            Err(e) => e,
+        };
+        let left = match self.fetch_episode_files(series_id).await {
+            Ok(left) => left,
+            Err(e) => {
+                return SeasonDeletion::Failed(
+                    format!("{} (and listing the remaining files failed: {})", error, e).into(),
+                )
+            }
+        };
+        let (remaining, deleted): (Vec<u32>, Vec<u32>) = files
+            .iter()
+            .map(|f| f.id)
+            .partition(|id| left.iter().any(|f| f.id == *id));
+        if remaining.is_empty() {
+            debug!(
+                "sonarr: all files of series {} are gone despite an error: {}",
+                series_id, error
+            );
+            SeasonDeletion::Complete
+        } else if deleted.is_empty() {
+            SeasonDeletion::Failed(error)
+        } else {
+            SeasonDeletion::Partial {
+                deleted,
+                remaining,
+                error,
+            }
+        }
+    }
+
+    /// Deletes [`EpisodeFile`]s one by one, stopping at the first
+    /// failure.
+    async fn delete_episode_files(&self, files: &[&EpisodeFile]) -> Result<(), Box<dyn Error>> {
+        for file in files.iter() {
+            self.delete_episode_file(file).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes [`EpisodeFile`]s in one request to the v3 API.
+    async fn bulk_delete_episode_files(
+        &self,
+        files: &[&EpisodeFile],
+    ) -> Result<(), Box<dyn Error>> {
+        let url = self.api_url("episodefile/bulk").await?;
+        let ids: Vec<u32> = files.iter().map(|f| f.id).collect();
+        let body = json!({ "episodeFileIds": ids });
+        self.http
+            .send(|c| c.delete(url.clone()).json(&body))
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(url: &str) -> String {
+        server_url(&Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn server_url_strips_the_api_path() {
+        assert_eq!(server("http://sonarr:8989/api/"), "http://sonarr:8989/");
+        assert_eq!(server("http://sonarr:8989/api/v3"), "http://sonarr:8989/");
+        assert_eq!(server("http://sonarr:8989/"), "http://sonarr:8989/");
+    }
+
+    #[test]
+    fn server_url_keeps_a_proxy_prefix() {
+        assert_eq!(
+            server("https://proxy/sonarr/api/v3/"),
+            "https://proxy/sonarr"
+        );
+        assert_eq!(server("https://proxy/sonarr/api"), "https://proxy/sonarr");
+        assert_eq!(server("https://proxy/sonarr/"), "https://proxy/sonarr");
+    }
 }
@@ -0,0 +1,151 @@
+//! A common interface to the media servers that keep track of watched states.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::config;
+use crate::services::{jellyfin, plex};
+
+/// Identifies a season on a media server: The name of the show and
+/// the title of the season (e.g., `"Season 3"`).
+pub type SeasonKey = (String, String);
+
+/// How much of a season a viewer has watched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WatchState {
+    /// Number of episodes in the season that the media server knows about.
+    pub episodes: u32,
+
+    /// Number of those episodes that have been watched.
+    pub viewed_episodes: u32,
+}
+
+impl WatchState {
+    /// True if there are no unwatched episodes left in the season.
+    pub fn fully_watched(&self) -> bool {
+        self.viewed_episodes >= self.episodes
+    }
+}
+
+fn plex_states(seasons: Vec<plex::Season>) -> HashMap<SeasonKey, WatchState> {
+    seasons
+        .into_iter()
+        .map(|s| {
+            let state = WatchState {
+                episodes: s.episodes,
+                viewed_episodes: s.viewed_episodes,
+            };
+            ((s.show_name, s.title), state)
+        })
+        .collect()
+}
+
+fn jellyfin_states(seasons: Vec<jellyfin::Season>) -> HashMap<SeasonKey, WatchState> {
+    seasons
+        .into_iter()
+        .map(|s| {
+            let state = s.watch_state();
+            ((s.series_name, s.name), state)
+        })
+        .collect()
+}
+
+/// A client for the media server that we consult for watched states.
+pub enum ViewerClient {
+    /// A Plex media server.
+    Plex(plex::PlexClient),
+
+    /// A Jellyfin or Emby media server.
+    Jellyfin(jellyfin::JellyfinClient),
+}
+
+impl ViewerClient {
+    /// Constructs the client that corresponds to the viewer configuration.
+    pub async fn from_config(conf: &config::Viewer) -> Result<ViewerClient> {
+        Ok(match conf {
+            config::Viewer::Plex(plex) => ViewerClient::Plex(
+                plex::PlexClient::from_config(plex).map_err(|e| anyhow!("plex: {}", e))?,
+            ),
+            config::Viewer::Jellyfin(jf) => {
+                ViewerClient::Jellyfin(jellyfin::JellyfinClient::from_config(jf).await?)
+            }
+        })
+    }
+
+    /// Asks the media server to notice that files of the series
+    /// with the given title were deleted. `path` is the folder that
+    /// Sonarr keeps the series in; Plex scans only that folder if it
+    /// can, and empties its trash afterwards with `empty_plex_trash`.
+    pub async fn refresh_series(
+        &self,
+        title: &str,
+        path: Option<&Path>,
+        empty_plex_trash: bool,
+    ) -> Result<()> {
+        match self {
+            ViewerClient::Plex(plex) => plex
+                .refresh_show(title, path, empty_plex_trash)
+                .await
+                .map_err(|e| anyhow!("plex: refreshing {}: {}", title, e)),
+            ViewerClient::Jellyfin(jf) => jf
+                .refresh_series(title)
+                .await
+                .map_err(|e| anyhow!("jellyfin: refreshing {}: {}", title, e)),
+        }
+    }
+
+    /// Human-readable name of the media server.
+    pub fn service_name(&self) -> &'static str {
+        match self {
+            ViewerClient::Plex(_) => "plex",
+            ViewerClient::Jellyfin(_) => "jellyfin",
+        }
+    }
+
+    /// Returns the watch state of every TV season on the media server.
+    pub async fn all_watch_states(&self) -> Result<HashMap<SeasonKey, WatchState>> {
+        Ok(match self {
+            ViewerClient::Plex(plex) => plex_states(
+                plex.all_tv_seasons()
+                    .await
+                    .map_err(|e| anyhow!("plex season listing: {}", e))?,
+            ),
+            ViewerClient::Jellyfin(jf) => jellyfin_states(jf.all_tv_seasons().await?),
+        })
+    }
+
+    /// Returns the title and ID (as taken by
+    /// [`series_watch_states`](Self::series_watch_states)) of every TV
+    /// show on the media server.
+    pub async fn shows(&self) -> Result<Vec<(String, String)>> {
+        Ok(match self {
+            ViewerClient::Plex(plex) => plex
+                .all_shows()
+                .await
+                .map_err(|e| anyhow!("plex show listing: {}", e))?
+                .into_iter()
+                .map(|show| (show.title, show.rating_key))
+                .collect(),
+            ViewerClient::Jellyfin(jf) => jf.all_series().await?,
+        })
+    }
+
+    /// Returns the watch state of every season of a single series,
+    /// identified by the media server's ID for it.
+    pub async fn series_watch_states(
+        &self,
+        series_id: &str,
+    ) -> Result<HashMap<SeasonKey, WatchState>> {
+        Ok(match self {
+            ViewerClient::Plex(plex) => plex_states(
+                plex.show_seasons(series_id)
+                    .await
+                    .map_err(|e| anyhow!("plex season listing: {}", e))?,
+            ),
+            ViewerClient::Jellyfin(jf) => jellyfin_states(jf.series_seasons(series_id).await?),
+        })
+    }
+}
@@ -0,0 +1,267 @@
+//! Statistics about the space that the TV library takes up.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::plan::{self, Policy};
+use crate::services::sonarr::Series;
+use crate::services::viewer::{SeasonKey, WatchState};
+
+/// How a report is printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    /// A table for humans.
+    #[default]
+    Table,
+
+    /// A JSON document.
+    Json,
+
+    /// Comma-separated values, one row per series.
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(anyhow!(
+                "Unknown format {:?}, expected table, json or csv",
+                s
+            )),
+        }
+    }
+}
+
+/// Space taken up by a single series.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SeriesStats {
+    /// Sonarr ID of the series.
+    pub series_id: u32,
+
+    /// Title of the series.
+    pub title: String,
+
+    /// Number of episode files on disk.
+    pub episode_files: u32,
+
+    /// Space taken up by all seasons.
+    pub size_bytes: u128,
+
+    /// Space taken up by the seasons that were watched completely.
+    pub watched_bytes: u128,
+
+    /// Space taken up by the seasons with unwatched episodes.
+    pub unwatched_bytes: u128,
+
+    /// Space that the current retention policy would free up.
+    pub reclaimable_bytes: u128,
+
+    /// True if not a single episode of the series was watched.
+    pub never_watched: bool,
+}
+
+/// Statistics about the whole TV library.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LibraryStats {
+    /// Number of episode files on disk.
+    pub episode_files: u32,
+
+    /// Space taken up by all series.
+    pub size_bytes: u128,
+
+    /// Space taken up by the seasons that were watched completely.
+    pub watched_bytes: u128,
+
+    /// Space taken up by the seasons with unwatched episodes.
+    pub unwatched_bytes: u128,
+
+    /// Space that the current retention policy would free up.
+    pub reclaimable_bytes: u128,
+
+    /// Every series, largest first.
+    pub series: Vec<SeriesStats>,
+}
+
+impl LibraryStats {
+    /// Aggregates the Sonarr statistics of `serieses` and their watch
+    /// states in `watched`.
+    pub fn collect(
+        policy: &Policy,
+        serieses: &[Series],
+        watched: &HashMap<SeasonKey, WatchState>,
+        now: DateTime<Utc>,
+    ) -> LibraryStats {
+        let mut by_series: Vec<SeriesStats> = serieses
+            .iter()
+            .map(|series| SeriesStats {
+                series_id: series.id,
+                title: series.title.clone(),
+                never_watched: true,
+                ..Default::default()
+            })
+            .collect();
+        let index: HashMap<u32, usize> = serieses
+            .iter()
+            .enumerate()
+            .map(|(i, series)| (series.id, i))
+            .collect();
+
+        for decision in plan::evaluate(policy, serieses, watched, now) {
+            let stats = &mut by_series[index[&decision.series.id]];
+            let season = &decision.season.statistics;
+            stats.episode_files += season.episode_file_count;
+            stats.size_bytes += season.size_on_disk;
+            match decision.watch_state {
+                Some(state) if state.fully_watched() => stats.watched_bytes += season.size_on_disk,
+                _ => stats.unwatched_bytes += season.size_on_disk,
+            }
+            if decision.watch_state.map(|s| s.viewed_episodes > 0) == Some(true) {
+                stats.never_watched = false;
+            }
+            if decision.is_eligible() {
+                stats.reclaimable_bytes += season.size_on_disk;
+            }
+        }
+        by_series.sort_by_key(|s| Reverse(s.size_bytes));
+
+        let mut library = LibraryStats::default();
+        for stats in by_series.iter() {
+            library.episode_files += stats.episode_files;
+            library.size_bytes += stats.size_bytes;
+            library.watched_bytes += stats.watched_bytes;
+            library.unwatched_bytes += stats.unwatched_bytes;
+            library.reclaimable_bytes += stats.reclaimable_bytes;
+        }
+        library.series = by_series;
+        library
+    }
+
+    /// Returns the series that nobody watched a single episode of,
+    /// but that take up space.
+    pub fn never_watched(&self) -> impl Iterator<Item = &SeriesStats> {
+        self.series
+            .iter()
+            .filter(|s| s.never_watched && s.size_bytes > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::json;
+
+    fn now() -> DateTime<Utc> {
+        "2020-06-01T00:00:00Z".parse().unwrap()
+    }
+
+    fn season(number: u32, size: u128) -> serde_json::Value {
+        json!({
+            "seasonNumber": number,
+            "monitored": true,
+            "statistics": {
+                "episodeFileCount": 2,
+                "totalEpisodeCount": 2,
+                "episodeCount": 2,
+                "previousAiring": "2020-01-01T00:00:00Z",
+                "nextAiring": null,
+                "sizeOnDisk": size,
+            },
+        })
+    }
+
+    fn series(id: u32, title: &str, seasons: Vec<serde_json::Value>) -> Series {
+        serde_json::from_value(json!({
+            "title": title,
+            "id": id,
+            "tags": [],
+            "seasons": seasons,
+        }))
+        .unwrap()
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            retain_tag: None,
+            retain_duration: Duration::days(14),
+            warn_before: Duration::zero(),
+        }
+    }
+
+    fn watched(title: &str, season: u32, viewed_episodes: u32) -> (SeasonKey, WatchState) {
+        (
+            (title.to_string(), format!("Season {}", season)),
+            WatchState {
+                episodes: 2,
+                viewed_episodes,
+            },
+        )
+    }
+
+    #[test]
+    fn splits_watched_and_unwatched_space() {
+        let serieses = vec![series(1, "Show", vec![season(1, 1000), season(2, 500)])];
+        let watched = vec![watched("Show", 1, 2), watched("Show", 2, 1)]
+            .into_iter()
+            .collect();
+        let stats = LibraryStats::collect(&policy(), &serieses, &watched, now());
+
+        assert_eq!(stats.episode_files, 4);
+        assert_eq!(stats.size_bytes, 1500);
+        assert_eq!(stats.watched_bytes, 1000);
+        assert_eq!(stats.unwatched_bytes, 500);
+        assert_eq!(stats.reclaimable_bytes, 1000);
+        assert!(!stats.series[0].never_watched);
+        assert_eq!(stats.never_watched().count(), 0);
+    }
+
+    #[test]
+    fn counts_seasons_without_watch_state_as_unwatched() {
+        let serieses = vec![series(1, "Show", vec![season(1, 1000)])];
+        let stats = LibraryStats::collect(&policy(), &serieses, &HashMap::new(), now());
+
+        assert_eq!(stats.watched_bytes, 0);
+        assert_eq!(stats.unwatched_bytes, 1000);
+        assert_eq!(stats.reclaimable_bytes, 0);
+        let never: Vec<_> = stats.never_watched().map(|s| s.series_id).collect();
+        assert_eq!(never, vec![1]);
+    }
+
+    #[test]
+    fn sorts_series_largest_first_and_sums_them() {
+        let serieses = vec![
+            series(1, "Small", vec![season(1, 100)]),
+            series(2, "Large", vec![season(1, 3000)]),
+            series(3, "Medium", vec![season(1, 800), season(2, 700)]),
+        ];
+        let watched = vec![watched("Medium", 1, 2)].into_iter().collect();
+        let stats = LibraryStats::collect(&policy(), &serieses, &watched, now());
+
+        let order: Vec<_> = stats.series.iter().map(|s| s.series_id).collect();
+        assert_eq!(order, vec![2, 3, 1]);
+        assert_eq!(stats.size_bytes, 4600);
+        assert_eq!(stats.watched_bytes, 800);
+        assert_eq!(stats.unwatched_bytes, 3800);
+        assert_eq!(stats.reclaimable_bytes, 800);
+        assert_eq!(stats.series[1].reclaimable_bytes, 800);
+    }
+
+    #[test]
+    fn leaves_empty_series_out_of_never_watched() {
+        let serieses = vec![series(1, "Empty", vec![season(1, 0)])];
+        let stats = LibraryStats::collect(&policy(), &serieses, &HashMap::new(), now());
+
+        assert!(stats.series[0].never_watched);
+        assert_eq!(stats.never_watched().count(), 0);
+    }
+}
@@ -0,0 +1,603 @@
+//! An HTTP API and web UI for reviewing the cleanup plan.
+//!
+//! Users log in with HTTP basic auth (see [`config::WebSettings`]),
+//! and can look at the seasons that are eligible for deletion, keep
+//! them (by tagging the series with the retain tag) or approve their
+//! deletion right away.
+//!
+//! Browsers send the basic auth credentials along with any request,
+//! including ones that other sites make them send. So POST requests
+//! are refused if their `Origin` (or `Referer`) is another site, and
+//! the UI's forms carry a per-user token that other sites can't know.
+//!
+//! The server also receives "watched" webhooks from Plex and
+//! Jellyfin. Watch states are fetched once at startup and then kept
+//! up to date by these webhooks, which trigger an evaluation of just
+//! the series that was watched.
+//!
+//! Sonarr's webhooks get recorded in the audit log, so that we notice
+//! when a season that was cleaned up receives new files.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use reqwest::Url;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::audit::{Entry, Event};
+use crate::cleaner::{Action, Cleaner};
+use crate::config::{self, SonarrPlexCleanerCliConfig, Viewer};
+use crate::plan::{format_size, PlanItem};
+use crate::prelude::*;
+use crate::runtime::block_on;
+use crate::services::viewer::{SeasonKey, WatchState};
+use crate::services::{jellyfin, plex, sonarr};
+
+/// How many audit log entries the UI shows.
+const AUDIT_ENTRIES: usize = 50;
+
+/// How many requests are handled at the same time.
+const WORKERS: usize = 4;
+
+/// Name of the form field that carries the CSRF token.
+const CSRF_FIELD: &str = "csrf";
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn text(status: u16, body: impl Into<String>) -> HttpResponse {
+    Response::from_string(body.into())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+fn html(body: String) -> HttpResponse {
+    Response::from_string(body).with_header(header("Content-Type", "text/html; charset=utf-8"))
+}
+
+fn json<T: Serialize>(result: Result<T>) -> HttpResponse {
+    match result.and_then(|value| Ok(serde_json::to_string(&value)?)) {
+        Ok(body) => {
+            Response::from_string(body).with_header(header("Content-Type", "application/json"))
+        }
+        Err(e) => text(500, e.to_string()),
+    }
+}
+
+fn redirect_home(result: Result<()>) -> HttpResponse {
+    match result {
+        Ok(()) => text(303, "").with_header(header("Location", "/")),
+        Err(e) => text(500, e.to_string()),
+    }
+}
+
+/// Returns the value of the header `name` of `request`.
+fn request_header<'r>(request: &'r Request, name: &str) -> Option<&'r str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+/// Returns the `host[:port]` part of a URL, as it appears in the
+/// `Host` header.
+fn authority(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+/// False if the request was sent from a page on another site.
+/// Browsers name the page's site in the `Origin` header (older ones
+/// only in `Referer`); other clients usually send neither.
+fn same_origin(request: &Request) -> bool {
+    let host = match request_header(request, "Host") {
+        Some(host) => host,
+        None => return false,
+    };
+    let source =
+        match request_header(request, "Origin").or_else(|| request_header(request, "Referer")) {
+            Some(source) => source,
+            None => return true,
+        };
+    Url::parse(source)
+        .ok()
+        .and_then(|url| authority(&url))
+        .is_some_and(|authority| authority.eq_ignore_ascii_case(host))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Returns the contents of the field `name` in a `multipart/form-data` body.
+fn multipart_field<'b>(content_type: &str, body: &'b [u8], name: &str) -> Option<&'b [u8]> {
+    let boundary = content_type
+        .split(';')
+        .map(str::trim)
+        .find_map(|param| param.strip_prefix("boundary="))?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary);
+    let disposition = format!("name=\"{}\"", name);
+
+    let mut rest = &body[find(body, delimiter.as_bytes())? + delimiter.len()..];
+    while let Some(end) = find(rest, delimiter.as_bytes()) {
+        let part = &rest[..end];
+        rest = &rest[end + delimiter.len()..];
+        let header_end = match find(part, b"\r\n\r\n") {
+            Some(header_end) => header_end,
+            None => continue,
+        };
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        if headers.contains(&disposition) {
+            let content = &part[header_end + 4..];
+            return Some(content.strip_suffix(b"\r\n").unwrap_or(content));
+        }
+    }
+    None
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Serves the web UI.
+pub struct WebServer<'a> {
+    cleaner: &'a Cleaner,
+    settings: &'a config::WebSettings,
+
+    /// The Jellyfin user whose webhook events we listen to.
+    jellyfin_user: Option<&'a str>,
+
+    /// Whether seasons that become eligible through a webhook get deleted.
+    delete_files: bool,
+
+    /// The most recently known watch states.
+    watched: Mutex<HashMap<SeasonKey, WatchState>>,
+
+    /// The token that each user's forms must carry, by user name.
+    csrf_tokens: Mutex<HashMap<String, String>>,
+}
+
+impl<'a> WebServer<'a> {
+    /// Constructs a web server that acts through `cleaner`.
+    pub fn new(
+        cleaner: &'a Cleaner,
+        config: &'a SonarrPlexCleanerCliConfig,
+        delete_files: bool,
+    ) -> Result<WebServer<'a>> {
+        if config.web.users.is_empty() {
+            return Err(anyhow!("No users configured in the [web] section"));
+        }
+        let jellyfin_user = match &config.viewer {
+            Viewer::Jellyfin(jf) => Some(jf.user.as_str()),
+            Viewer::Plex(_) => None,
+        };
+        let watched = Mutex::new(block_on(cleaner.viewer.all_watch_states())?);
+        Ok(WebServer {
+            cleaner,
+            settings: &config.web,
+            jellyfin_user,
+            delete_files,
+            watched,
+            csrf_tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Handles requests until the listening socket fails. Several
+    /// requests are handled at a time, so that one that waits for a
+    /// slow server doesn't hold up the others.
+    pub fn serve(&self) -> Result<()> {
+        let server = Server::http(self.settings.listen).map_err(|e| anyhow!("{}", e))?;
+        info!("Listening on http://{}", self.settings.listen);
+        thread::scope(|scope| {
+            for _ in 0..WORKERS {
+                scope.spawn(|| {
+                    for request in server.incoming_requests() {
+                        self.handle(request);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    /// Returns the name of the user that the request authenticates as.
+    fn authenticate(&self, request: &Request) -> Option<String> {
+        let encoded = request_header(request, "Authorization")?.strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+        let mut parts = decoded.splitn(2, ':');
+        let (name, password) = (parts.next()?, parts.next()?);
+        self.settings
+            .users
+            .iter()
+            .find(|u| u.name == name && u.password.expose_secret().matches(password))
+            .map(|u| u.name.clone())
+    }
+
+    /// True if the request carries the configured webhook token.
+    fn webhook_authorized(&self, query: &str) -> bool {
+        let token = match &self.settings.webhook_token {
+            Some(token) => token,
+            None => return false,
+        };
+        form_urlencoded::parse(query.as_bytes())
+            .any(|(k, v)| k == "token" && token.expose_secret().matches(&v))
+    }
+
+    /// Returns the token that `user`'s forms carry, making one up on
+    /// first use.
+    fn csrf_token(&self, user: &str) -> String {
+        let mut tokens = self.csrf_tokens.lock().expect("csrf token lock");
+        tokens
+            .entry(user.to_string())
+            .or_insert_with(|| {
+                let bytes: [u8; 32] = rand::random();
+                base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+            })
+            .clone()
+    }
+
+    /// True if the form in the body of `request` carries `user`'s
+    /// token.
+    fn csrf_valid(&self, user: &str, request: &mut Request) -> bool {
+        let mut body = vec![];
+        if request.as_reader().read_to_end(&mut body).is_err() {
+            return false;
+        }
+        let expected = self.csrf_token(user);
+        form_urlencoded::parse(&body)
+            .any(|(k, v)| k == CSRF_FIELD && bool::from(v.as_bytes().ct_eq(expected.as_bytes())))
+    }
+
+    fn handle(&self, mut request: Request) {
+        let url = request.url().to_string();
+        let mut url_parts = url.splitn(2, '?');
+        let path = url_parts.next().unwrap_or("").to_string();
+        let query = url_parts.next().unwrap_or("");
+        if path.starts_with("/webhooks/") {
+            let response = if self.webhook_authorized(query) {
+                match self.webhook(&path, &mut request) {
+                    Ok(()) => text(200, "ok"),
+                    Err(e) => {
+                        warn!("Handling webhook {}: {}", path, e);
+                        text(500, e.to_string())
+                    }
+                }
+            } else {
+                text(403, "Forbidden")
+            };
+            let _ = request.respond(response);
+            return;
+        }
+
+        let user = match self.authenticate(&request) {
+            Some(user) => user,
+            None => {
+                let response = text(401, "Authentication required").with_header(header(
+                    "WWW-Authenticate",
+                    "Basic realm=\"sonarr-plex-cleaner\"",
+                ));
+                let _ = request.respond(response);
+                return;
+            }
+        };
+        let method = request.method().clone();
+        debug!("{} {} {}", user, method, path);
+        if method == Method::Post && !same_origin(&request) {
+            warn!(
+                "Refusing {} {} for {} from another site",
+                method, path, user
+            );
+            let _ = request.respond(text(403, "Cross-site requests are not allowed"));
+            return;
+        }
+
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let response = match (&method, segments.as_slice()) {
+            (Method::Get, [""]) => self.index(&user),
+            (Method::Get, ["api", "plan"]) => json(self.plan()),
+            (Method::Get, ["api", "audit"]) => json(self.cleaner.audit.recent(AUDIT_ENTRIES)),
+            (Method::Post, ["api", "seasons", series, season, action]) => {
+                json(self.act(&user, series, season, action))
+            }
+            (Method::Post, ["seasons", series, season, action]) => {
+                if self.csrf_valid(&user, &mut request) {
+                    redirect_home(self.act(&user, series, season, action))
+                } else {
+                    text(403, "The form has expired, reload the page and try again")
+                }
+            }
+            _ => text(404, "Not found"),
+        };
+        if let Err(e) = request.respond(response) {
+            warn!("Could not respond to {} {}: {}", method, path, e);
+        }
+    }
+
+    /// Keeps or deletes a season on behalf of `user`.
+    fn act(&self, user: &str, series: &str, season: &str, action: &str) -> Result<()> {
+        let series_id: u32 = series.parse()?;
+        let season: u32 = season.parse()?;
+        let actor = format!("web:{}", user);
+        match action {
+            "keep" => block_on(self.cleaner.retain_series(&actor, series_id)),
+            "approve" => {
+                let watched = self.watch_states();
+                block_on(
+                    self.cleaner
+                        .delete_season(&actor, series_id, season, &watched),
+                )
+            }
+            _ => Err(anyhow!("Unknown action {:?}", action)),
+        }
+    }
+
+    /// Returns a copy of the most recently known watch states, so that
+    /// the lock isn't held while talking to the servers.
+    fn watch_states(&self) -> HashMap<SeasonKey, WatchState> {
+        self.watched.lock().expect("watch state lock").clone()
+    }
+
+    fn plan(&self) -> Result<Vec<PlanItem>> {
+        block_on(self.cleaner.plan_with(&self.watch_states()))
+    }
+
+    /// Handles a "watched" event from the media server.
+    fn webhook(&self, path: &str, request: &mut Request) -> Result<()> {
+        let content_type = request_header(request, "Content-Type")
+            .unwrap_or_default()
+            .to_string();
+        let mut body = vec![];
+        request.as_reader().read_to_end(&mut body)?;
+
+        let service = self.cleaner.viewer.service_name();
+        match path {
+            "/webhooks/plex" if service == "plex" => {
+                let payload = multipart_field(&content_type, &body, "payload")
+                    .ok_or_else(|| anyhow!("No payload in plex webhook"))?;
+                let event: plex::WebhookEvent = serde_json::from_slice(payload)?;
+                match event.watched_show() {
+                    Some((id, title)) => self.reevaluate("webhook:plex", id, title),
+                    None => Ok(()),
+                }
+            }
+            "/webhooks/jellyfin" if service == "jellyfin" => {
+                let event: jellyfin::WebhookEvent = serde_json::from_slice(&body)?;
+                let user = self.jellyfin_user.unwrap_or_default();
+                match event.watched_series(user) {
+                    Some((id, title)) => self.reevaluate("webhook:jellyfin", id, title),
+                    None => Ok(()),
+                }
+            }
+            "/webhooks/sonarr" => {
+                let event: sonarr::WebhookEvent = serde_json::from_slice(&body)?;
+                self.sonarr_event(&event)
+            }
+            _ => Err(anyhow!("No webhook at {} for {}", path, service)),
+        }
+    }
+
+    /// Records a change that Sonarr made in the audit log.
+    fn sonarr_event(&self, event: &sonarr::WebhookEvent) -> Result<()> {
+        let series = match &event.series {
+            Some(series) => series,
+            None => return Ok(()), // e.g. the "Test" event
+        };
+        let entry = |event_kind, season| Entry {
+            time: Utc::now(),
+            actor: "sonarr".to_string(),
+            event: event_kind,
+            series_id: series.id,
+            title: series.title.clone(),
+            season,
+            size_bytes: event.episode_file.as_ref().map(|f| f.size).unwrap_or(0),
+        };
+        match event.event_type.as_str() {
+            "Download" => {
+                for season in event.season_numbers() {
+                    if let Some(deleted) = self.cleaner.audit.last_deletion(series.id, season)? {
+                        warn!(
+                            "{} S{:02} was cleaned up on {}, but sonarr imported new files for it",
+                            series.title,
+                            season,
+                            deleted.time.format("%Y-%m-%d")
+                        );
+                    }
+                    self.cleaner
+                        .audit
+                        .record(&entry(Event::Downloaded, Some(season)))?;
+                }
+            }
+            "EpisodeFileDelete" => {
+                for season in event.season_numbers() {
+                    self.cleaner
+                        .audit
+                        .record(&entry(Event::FilesDeleted, Some(season)))?;
+                }
+            }
+            "SeriesDelete" => self
+                .cleaner
+                .audit
+                .record(&entry(Event::SeriesDeleted, None))?,
+            other => debug!("Ignoring sonarr event {:?}", other),
+        }
+        Ok(())
+    }
+
+    /// Refreshes the watch states of a single series and evaluates
+    /// only that series.
+    fn reevaluate(&self, actor: &str, series_id: &str, title: &str) -> Result<()> {
+        info!("{} was watched, re-evaluating it", title);
+        let states = block_on(self.cleaner.viewer.series_watch_states(series_id))?;
+        let watched = {
+            let mut watched = self.watched.lock().expect("watch state lock");
+            watched.extend(states);
+            watched.clone()
+        };
+        let delete = self.delete_files;
+        block_on(
+            self.cleaner
+                .run_series(actor, !delete, title, &watched, |_, _| {
+                    if delete {
+                        Action::Delete
+                    } else {
+                        Action::DryRun
+                    }
+                }),
+        )?;
+        Ok(())
+    }
+
+    fn index(&self, user: &str) -> HttpResponse {
+        let plan = match self.plan() {
+            Ok(plan) => plan,
+            Err(e) => return text(500, e.to_string()),
+        };
+        let audit = match self.cleaner.audit.recent(AUDIT_ENTRIES) {
+            Ok(audit) => audit,
+            Err(e) => return text(500, e.to_string()),
+        };
+        let can_keep = self.cleaner.policy.retain_tag.is_some();
+        let csrf = self.csrf_token(user);
+
+        let mut page = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+             <title>sonarr-plex-cleaner</title></head><body>\n\
+             <h1>Seasons to be deleted</h1>\n<table>\n\
+             <tr><th>Show</th><th>Season</th><th>Size</th><th>Last aired</th>\
+             <th>Watched</th><th></th></tr>\n",
+        );
+        for item in plan.iter() {
+            page.push_str(&self.plan_row(item, can_keep, &csrf));
+        }
+        page.push_str("</table>\n<h1>Recent changes</h1>\n<table>\n");
+        page.push_str(
+            "<tr><th>Time</th><th>By</th><th>Change</th><th>Show</th><th>Season</th>\
+             <th>Size</th></tr>\n",
+        );
+        for entry in audit.iter() {
+            page.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                entry.time.format("%Y-%m-%d %H:%M"),
+                escape(&entry.actor),
+                entry.event,
+                escape(&entry.title),
+                entry.season.map(|s| s.to_string()).unwrap_or_default(),
+                format_size(entry.size_bytes),
+            ));
+        }
+        page.push_str("</table>\n</body></html>\n");
+        html(page)
+    }
+
+    fn plan_row(&self, item: &PlanItem, can_keep: bool, csrf: &str) -> String {
+        let action = |name: &str, label: &str| {
+            format!(
+                "<form method=\"post\" action=\"/seasons/{}/{}/{}\" style=\"display:inline\">\
+                 <input type=\"hidden\" name=\"{}\" value=\"{}\">\
+                 <button>{}</button></form>",
+                item.series_id,
+                item.season,
+                name,
+                CSRF_FIELD,
+                escape(csrf),
+                label
+            )
+        };
+        format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}{}</td></tr>\n",
+            escape(&item.title),
+            item.season,
+            format_size(item.size_bytes),
+            item.previous_airing
+                .map(|t| t.format("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+            item.watch_state
+                .map(|w| format!("{}/{}", w.viewed_episodes, w.episodes))
+                .unwrap_or_default(),
+            if can_keep {
+                action("keep", "Keep show")
+            } else {
+                String::new()
+            },
+            action("approve", "Delete now"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=XyZ";
+
+    fn body(parts: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = String::new();
+        for (name, content) in parts {
+            body.push_str(&format!(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, content
+            ));
+        }
+        body.push_str("--XyZ--\r\n");
+        body.into_bytes()
+    }
+
+    #[test]
+    fn finds_named_field() {
+        let body = body(&[("thumb", "not json"), ("payload", "{\"event\":1}")]);
+        assert_eq!(
+            multipart_field(CONTENT_TYPE, &body, "payload"),
+            Some(&b"{\"event\":1}"[..])
+        );
+    }
+
+    #[test]
+    fn keeps_line_breaks_inside_the_field() {
+        let body = body(&[("payload", "line 1\r\nline 2")]);
+        assert_eq!(
+            multipart_field(CONTENT_TYPE, &body, "payload"),
+            Some(&b"line 1\r\nline 2"[..])
+        );
+    }
+
+    #[test]
+    fn accepts_quoted_boundary() {
+        let body = body(&[("payload", "{}")]);
+        let content_type = "multipart/form-data; boundary=\"XyZ\"";
+        assert_eq!(
+            multipart_field(content_type, &body, "payload"),
+            Some(&b"{}"[..])
+        );
+    }
+
+    #[test]
+    fn missing_field_or_boundary() {
+        let body = body(&[("thumb", "{}")]);
+        assert_eq!(multipart_field(CONTENT_TYPE, &body, "payload"), None);
+        assert_eq!(multipart_field("multipart/form-data", &body, "thumb"), None);
+        assert_eq!(
+            multipart_field(CONTENT_TYPE, b"no parts here", "thumb"),
+            None
+        );
+    }
+}
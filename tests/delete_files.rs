@@ -0,0 +1,333 @@
+mod support;
+
+use abscissa_core::testing::CmdRunner;
+use fs2::FileExt;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use support::{
+    episode_file, season, series, test_dir, write_config, FakeJellyfin, FakePlex, FakeSonarr,
+    SonarrState, WatchedSeason,
+};
+
+const LONG_AGO: &str = "2020-03-01T20:00:00Z";
+const FAR_AHEAD: &str = "2099-01-01T20:00:00Z";
+
+/// Sonarr knows about:
+/// * "Finished Show", with a fully watched season 1 (files 11, 12)
+///   and a partially watched season 2 (file 21),
+/// * "Running Show", fully watched but still airing (file 31),
+/// * "Kept Show", fully watched but tagged `retain` (file 41).
+fn sonarr() -> FakeSonarr {
+    FakeSonarr::start(sonarr_state())
+}
+
+fn sonarr_state() -> SonarrState {
+    SonarrState {
+        series: vec![
+            series(
+                1,
+                "Finished Show",
+                &[],
+                vec![
+                    season(1, LONG_AGO, None, 2000),
+                    season(2, LONG_AGO, None, 1000),
+                ],
+            ),
+            series(
+                2,
+                "Running Show",
+                &[],
+                vec![season(1, LONG_AGO, Some(FAR_AHEAD), 1000)],
+            ),
+            series(3, "Kept Show", &[7], vec![season(1, LONG_AGO, None, 1000)]),
+        ],
+        tags: vec![json!({"id": 7, "label": "retain"})],
+        episode_files: vec![
+            episode_file(11, 1, 1, 1000),
+            episode_file(12, 1, 1, 1000),
+            episode_file(21, 1, 2, 1000),
+            episode_file(31, 2, 1, 1000),
+            episode_file(41, 3, 1, 1000),
+        ],
+        ..SonarrState::default()
+    }
+}
+
+fn watched() -> Vec<WatchedSeason> {
+    vec![
+        WatchedSeason::new("Finished Show", 1, 2, 2),
+        WatchedSeason::new("Finished Show", 2, 2, 1),
+        WatchedSeason::new("Running Show", 1, 2, 2),
+        WatchedSeason::new("Kept Show", 1, 2, 2),
+    ]
+}
+
+const RETENTION: &str = r#"
+[retention]
+retain_tag = "retain"
+retain_duration = "14d"
+"#;
+
+fn plex_servers(sonarr: &FakeSonarr, plex: &FakePlex) -> String {
+    format!(
+        "[tv]\nurl = {:?}\napi_key = \"sonarr-key\"\n\n[plex]\nurl = {:?}\napi_key = \"plex-key\"\n",
+        sonarr.url(),
+        plex.url()
+    )
+}
+
+fn run_tv(config: &Path, args: &[&str]) {
+    tv_runner(config, args).status().expect_success();
+}
+
+fn tv_runner(config: &Path, args: &[&str]) -> CmdRunner {
+    let mut runner = CmdRunner::default();
+    runner
+        .args(["-c", config.to_str().unwrap(), "tv"])
+        .args(args);
+    runner
+}
+
+fn season_monitored(sonarr: &FakeSonarr, series_id: u32, number: u32) -> bool {
+    let state = sonarr.state();
+    state.series_by_id(series_id)["seasons"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|s| s["seasonNumber"] == number)
+        .unwrap()["monitored"]
+        .as_bool()
+        .unwrap()
+}
+
+#[test]
+fn dry_run_deletes_nothing() {
+    let dir = test_dir("dry-run");
+    let sonarr = sonarr();
+    let plex = FakePlex::start(watched());
+    let config = write_config(&dir, &plex_servers(&sonarr, &plex), RETENTION);
+
+    run_tv(&config, &[]);
+
+    assert!(sonarr.state().deleted_files.is_empty());
+    assert!(season_monitored(&sonarr, 1, 1));
+}
+
+#[test]
+fn deletes_watched_seasons_with_plex() {
+    let dir = test_dir("delete-plex");
+    let sonarr = sonarr();
+    let plex = FakePlex::start(watched());
+    let config = write_config(&dir, &plex_servers(&sonarr, &plex), RETENTION);
+
+    run_tv(&config, &["--delete-files"]);
+
+    assert_eq!(sonarr.state().deleted_files, vec![11, 12]);
+    assert!(!season_monitored(&sonarr, 1, 1));
+    assert!(season_monitored(&sonarr, 1, 2));
+    assert!(season_monitored(&sonarr, 3, 1));
+}
+
+#[test]
+fn deletes_in_bulk_with_sonarr_v3() {
+    let dir = test_dir("delete-v3");
+    let sonarr = FakeSonarr::start(SonarrState {
+        v3: true,
+        ..sonarr_state()
+    });
+    let plex = FakePlex::start(watched());
+    let config = write_config(&dir, &plex_servers(&sonarr, &plex), RETENTION);
+
+    run_tv(&config, &["--delete-files"]);
+
+    let state = sonarr.state();
+    assert_eq!(state.deleted_files, vec![11, 12]);
+    assert!(state.requests.contains(&"POST seasonpass".to_string()));
+    assert!(state
+        .requests
+        .contains(&"DELETE episodefile/bulk".to_string()));
+    drop(state);
+    assert!(!season_monitored(&sonarr, 1, 1));
+    assert!(season_monitored(&sonarr, 1, 2));
+}
+
+#[test]
+fn deletes_watched_seasons_with_jellyfin() {
+    let dir = test_dir("delete-jellyfin");
+    let sonarr = sonarr();
+    let jellyfin = FakeJellyfin::start(&["someone", "me"], watched());
+    let servers = format!(
+        "[tv]\nurl = {:?}\napi_key = \"sonarr-key\"\n\n[jellyfin]\nuser = \"me\"\n\n[jellyfin.server]\nurl = {:?}\napi_key = \"jellyfin-key\"\n",
+        sonarr.url(),
+        jellyfin.url()
+    );
+    let config = write_config(&dir, &servers, RETENTION);
+
+    run_tv(&config, &["--delete-files"]);
+
+    assert_eq!(sonarr.state().deleted_files, vec![11, 12]);
+    assert!(!season_monitored(&sonarr, 1, 1));
+}
+
+#[test]
+fn retention_period_protects_recent_seasons() {
+    let dir = test_dir("delete-recent");
+    let sonarr = sonarr();
+    let plex = FakePlex::start(watched());
+    let retention = r#"
+[retention]
+retain_duration = "100000d"
+"#;
+    let config = write_config(&dir, &plex_servers(&sonarr, &plex), retention);
+
+    run_tv(&config, &["--delete-files"]);
+
+    assert!(sonarr.state().deleted_files.is_empty());
+}
+
+#[test]
+fn refuses_to_run_while_another_run_holds_the_lock() {
+    let dir = test_dir("delete-locked");
+    let sonarr = sonarr();
+    let plex = FakePlex::start(watched());
+    let lock_file = dir.join("run.lock");
+    let extra = format!("{}\n[daemon]\nlock_file = {:?}\n", RETENTION, lock_file);
+    let config = write_config(&dir, &plex_servers(&sonarr, &plex), &extra);
+
+    // another run, e.g. the daemon's, is in progress:
+    let lock = fs::File::create(&lock_file).unwrap();
+    lock.try_lock_exclusive().unwrap();
+    tv_runner(&config, &["--delete-files"])
+        .status()
+        .expect_code(1);
+    assert!(sonarr.state().deleted_files.is_empty());
+
+    lock.unlock().unwrap();
+    run_tv(&config, &["--delete-files"]);
+    assert_eq!(sonarr.state().deleted_files, vec![11, 12]);
+}
+
+fn audit_events(dir: &Path) -> Vec<String> {
+    fs::read_to_string(dir.join("audit.jsonl"))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| {
+            let entry: Value = serde_json::from_str(line).unwrap();
+            format!("{} S{}", entry["event"].as_str().unwrap(), entry["season"])
+        })
+        .collect()
+}
+
+fn plex_servers_without_retries(sonarr: &FakeSonarr, plex: &FakePlex) -> String {
+    format!("{}\n[tv.http]\nretries = 0\n", plex_servers(sonarr, plex))
+}
+
+#[test]
+fn reports_partially_deleted_seasons() {
+    for v3 in &[false, true] {
+        let dir = test_dir(&format!("delete-partial-{}", v3));
+        let sonarr = FakeSonarr::start(SonarrState {
+            v3: *v3,
+            undeletable: vec![12],
+            ..sonarr_state()
+        });
+        let plex = FakePlex::start(watched());
+        let servers = plex_servers_without_retries(&sonarr, &plex);
+        let config = write_config(&dir, &servers, RETENTION);
+
+        tv_runner(&config, &["--delete-files"])
+            .status()
+            .expect_code(1);
+
+        assert_eq!(sonarr.state().deleted_files, vec![11]);
+        assert_eq!(audit_events(&dir), vec!["partially_deleted S1"]);
+    }
+}
+
+#[test]
+fn reports_seasons_where_nothing_was_deleted() {
+    let dir = test_dir("delete-none");
+    let sonarr = FakeSonarr::start(SonarrState {
+        v3: true,
+        undeletable: vec![11],
+        ..sonarr_state()
+    });
+    let plex = FakePlex::start(watched());
+    let servers = plex_servers_without_retries(&sonarr, &plex);
+    let config = write_config(&dir, &servers, RETENTION);
+
+    tv_runner(&config, &["--delete-files"])
+        .status()
+        .expect_code(1);
+
+    assert!(sonarr.state().deleted_files.is_empty());
+    assert!(audit_events(&dir).is_empty());
+}
+
+fn refreshes(requests: Vec<String>, prefix: &str) -> usize {
+    requests.iter().filter(|r| r.starts_with(prefix)).count()
+}
+
+#[test]
+fn refreshes_plex_after_deleting() {
+    let dir = test_dir("refresh-plex");
+    let sonarr = sonarr();
+    let plex = FakePlex::start(watched());
+    let config = write_config(&dir, &plex_servers(&sonarr, &plex), RETENTION);
+
+    run_tv(&config, &[]);
+    assert_eq!(
+        refreshes(plex.requests(), "GET library/sections/1/refresh"),
+        0
+    );
+
+    run_tv(&config, &["--delete-files"]);
+    let requests = plex.requests();
+    assert_eq!(
+        refreshes(
+            requests.clone(),
+            "GET library/sections/1/refresh?path=%2Ftv%2FFinished"
+        ),
+        1
+    );
+    assert_eq!(refreshes(requests, "PUT library/sections/1/emptyTrash"), 0);
+}
+
+#[test]
+fn empties_plex_trash_when_asked_to() {
+    let dir = test_dir("refresh-plex-trash");
+    let sonarr = sonarr();
+    let plex = FakePlex::start(watched());
+    let extra = format!("{}\n[refresh]\nempty_plex_trash = true\n", RETENTION);
+    let config = write_config(&dir, &plex_servers(&sonarr, &plex), &extra);
+
+    run_tv(&config, &["--delete-files"]);
+
+    assert_eq!(
+        refreshes(plex.requests(), "PUT library/sections/1/emptyTrash"),
+        1
+    );
+}
+
+#[test]
+fn refreshes_jellyfin_after_deleting() {
+    let dir = test_dir("refresh-jellyfin");
+    let sonarr = sonarr();
+    let jellyfin = FakeJellyfin::start(&["me"], watched());
+    let servers = format!(
+        "[tv]\nurl = {:?}\napi_key = \"sonarr-key\"\n\n[jellyfin]\nuser = \"me\"\n\n[jellyfin.server]\nurl = {:?}\napi_key = \"jellyfin-key\"\n",
+        sonarr.url(),
+        jellyfin.url()
+    );
+    let extra = format!("{}\n[refresh]\nlibrary = true\n", RETENTION);
+    let config = write_config(&dir, &servers, &extra);
+
+    run_tv(&config, &["--delete-files"]);
+
+    // "Finished Show" is the first series that the fake knows about:
+    assert_eq!(
+        jellyfin.requests().iter().filter(|r| r.starts_with("POST Items/")).collect::<Vec<_>>(),
+        vec!["POST Items/series0/Refresh?metadataRefreshMode=Default&imageRefreshMode=Default&replaceAllMetadata=false&replaceAllImages=false"]
+    );
+}
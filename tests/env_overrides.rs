@@ -0,0 +1,291 @@
+use secrecy::ExposeSecret;
+use sonarr_plex_cleaner::config::{
+    apply_env_overrides, ApiKeySource, SonarrPlexCleanerCliConfig, Viewer,
+};
+use std::time::Duration;
+
+const CONFIG: &str = r#"
+[tv]
+url = "https://sonarr.example.com/api/"
+api_key = "from-file"
+
+[plex]
+url = "http://plex.example.com:32400/"
+api_key = "from-file"
+
+[retention]
+retain_tag = "retain"
+retain_duration = "14d"
+"#;
+
+fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn key(source: &ApiKeySource) -> String {
+    source
+        .resolve()
+        .unwrap()
+        .expose_secret()
+        .as_str()
+        .to_string()
+}
+
+fn load(toml: &str, env: &[(&str, &str)]) -> SonarrPlexCleanerCliConfig {
+    let mut value: toml::Value = toml::from_str(toml).unwrap();
+    apply_env_overrides(&mut value, vars(env)).unwrap();
+    value.try_into().unwrap()
+}
+
+#[test]
+fn file_settings_without_overrides() {
+    let config = load(CONFIG, &[]);
+    assert_eq!(key(&config.tv.api_key), "from-file");
+    assert_eq!(
+        config.retention.retain_duration,
+        Duration::from_secs(14 * 24 * 60 * 60)
+    );
+}
+
+#[test]
+fn environment_overrides_file() {
+    let config = load(
+        CONFIG,
+        &[
+            ("SPC_TV__URL", "http://sonarr:8989/api/"),
+            ("SPC_PLEX__API_KEY", "from-env"),
+            ("SPC_RETENTION__RETAIN_DURATION", "1d"),
+        ],
+    );
+    assert_eq!(config.tv.url.as_str(), "http://sonarr:8989/api/");
+    match config.viewer {
+        Viewer::Plex(plex) => assert_eq!(key(&plex.api_key), "from-env"),
+        other => panic!("unexpected viewer {:?}", other),
+    }
+    assert_eq!(
+        config.retention.retain_duration,
+        Duration::from_secs(24 * 60 * 60)
+    );
+    assert_eq!(config.retention.retain_tag.as_deref(), Some("retain"));
+}
+
+#[test]
+fn string_settings_stay_strings() {
+    let config = load(CONFIG, &[("SPC_TV__API_KEY", "12345")]);
+    assert_eq!(key(&config.tv.api_key), "12345");
+}
+
+#[test]
+fn environment_creates_missing_sections() {
+    let config = load(
+        "",
+        &[
+            ("SPC_TV__URL", "http://sonarr:8989/api/"),
+            ("SPC_TV__API_KEY", "key"),
+            ("SPC_JELLYFIN__USER", "me"),
+            ("SPC_JELLYFIN__SERVER__URL", "http://jellyfin:8096/"),
+            ("SPC_JELLYFIN__SERVER__API_KEY", "key"),
+            ("SPC_RETENTION__RETAIN_DURATION", "2d"),
+            ("SPC_WEB__USERS__0__NAME", "admin"),
+            ("SPC_WEB__USERS__0__PASSWORD", "hunter2"),
+        ],
+    );
+    match config.viewer {
+        Viewer::Jellyfin(jellyfin) => assert_eq!(jellyfin.user, "me"),
+        other => panic!("unexpected viewer {:?}", other),
+    }
+    assert_eq!(config.web.users.len(), 1);
+    assert_eq!(config.web.users[0].name, "admin");
+}
+
+#[test]
+fn env_only_settings_keep_their_types() {
+    let config = load(
+        "",
+        &[
+            ("SPC_TV__URL", "http://sonarr:8989/api/"),
+            ("SPC_TV__API_KEY", "12345"),
+            ("SPC_TV__HTTP__RETRIES", "5"),
+            ("SPC_JELLYFIN__USER", "true"),
+            ("SPC_RETENTION__RETAIN_DURATION", "2d"),
+            ("SPC_JELLYFIN__SERVER__URL", "http://jellyfin:8096/"),
+            ("SPC_JELLYFIN__SERVER__API_KEY", "0x10"),
+            ("SPC_WEB__USERS__0__NAME", "admin"),
+            ("SPC_WEB__USERS__0__PASSWORD", "1234"),
+            ("SPC_REFRESH__LIBRARY", "false"),
+        ],
+    );
+    assert_eq!(key(&config.tv.api_key), "12345");
+    assert_eq!(config.tv.http.retries, 5);
+    match config.viewer {
+        Viewer::Jellyfin(jellyfin) => {
+            assert_eq!(jellyfin.user, "true");
+            assert_eq!(key(&jellyfin.server.api_key), "0x10");
+        }
+        other => panic!("unexpected viewer {:?}", other),
+    }
+    assert!(config.web.users[0].password.expose_secret().matches("1234"));
+    assert!(!config.refresh.library);
+}
+
+#[test]
+fn ignores_unrelated_variables() {
+    let config = load(
+        CONFIG,
+        &[("SPC_CONFIG", "/etc/spc.toml"), ("HOME", "/root")],
+    );
+    assert_eq!(key(&config.tv.api_key), "from-file");
+}
+
+#[test]
+fn rejects_settings_outside_sections() {
+    let mut value: toml::Value = toml::from_str(CONFIG).unwrap();
+    let result = apply_env_overrides(&mut value, vars(&[("SPC_TV__URL__EXTRA", "x")]));
+    assert!(result.is_err());
+}
+
+#[test]
+fn server_settings_take_toml_values() {
+    let config = load(
+        "",
+        &[
+            ("SPC_TV__URL", "http://sonarr:8989/api/"),
+            ("SPC_TV__API_KEY", "key"),
+            ("SPC_TV__BASIC_AUTH", r#"{ username = "proxy" }"#),
+            ("SPC_TV__HEADERS", r#"{ X-Token = "secret" }"#),
+            ("SPC_JELLYFIN__USER", "me"),
+            ("SPC_RETENTION__RETAIN_DURATION", "2d"),
+            ("SPC_JELLYFIN__SERVER__URL", "http://jellyfin:8096/"),
+            (
+                "SPC_JELLYFIN__SERVER__API_KEY_COMMAND",
+                r#"["echo", "from-command"]"#,
+            ),
+        ],
+    );
+    assert_eq!(config.tv.basic_auth.unwrap().username, "proxy");
+    assert_eq!(
+        config.tv.headers["X-Token"].expose_secret().as_str(),
+        "secret"
+    );
+    match config.viewer {
+        Viewer::Jellyfin(jellyfin) => {
+            match &jellyfin.server.api_key {
+                ApiKeySource::Command(command) => assert_eq!(command, &["echo", "from-command"]),
+                other => panic!("unexpected API key source {:?}", other),
+            }
+            assert_eq!(key(&jellyfin.server.api_key), "from-command");
+        }
+        other => panic!("unexpected viewer {:?}", other),
+    }
+}
+
+#[test]
+fn client_settings_take_toml_values() {
+    let config = load(
+        CONFIG,
+        &[
+            ("SPC_TV__TLS__DANGER_ACCEPT_INVALID_CERTS", "true"),
+            ("SPC_TV__HTTP__RETRIES", "7"),
+            ("SPC_TV__HTTP__MAX_CONCURRENT_REQUESTS", "3"),
+            ("SPC_TV__HTTP__REQUESTS_PER_SECOND", "2.5"),
+            ("SPC_PLEX__HTTP", "{ retries = 1 }"),
+            ("SPC_NOTIFICATIONS__WEBHOOKS__0__URL", "http://hook/"),
+            (
+                "SPC_NOTIFICATIONS__WEBHOOKS__0__TLS",
+                "{ danger_accept_invalid_certs = true }",
+            ),
+            ("SPC_NOTIFICATIONS__WEBHOOKS__0__HTTP__RETRIES", "4"),
+        ],
+    );
+    assert!(config.tv.tls.danger_accept_invalid_certs);
+    assert_eq!(config.tv.http.retries, 7);
+    assert_eq!(config.tv.http.max_concurrent_requests, Some(3));
+    assert_eq!(config.tv.http.requests_per_second, Some(2.5));
+    match config.viewer {
+        Viewer::Plex(plex) => assert_eq!(plex.http.retries, 1),
+        other => panic!("unexpected viewer {:?}", other),
+    }
+    let webhook = &config.notifications.webhooks[0];
+    assert!(webhook.tls.danger_accept_invalid_certs);
+    assert_eq!(webhook.http.retries, 4);
+}
+
+#[test]
+fn notification_and_refresh_settings_take_toml_values() {
+    let config = load(
+        CONFIG,
+        &[
+            ("SPC_NOTIFICATIONS__SMTP__SERVER", "25"),
+            ("SPC_NOTIFICATIONS__SMTP__PORT", "587"),
+            ("SPC_NOTIFICATIONS__SMTP__FROM", "spc@example.com"),
+            ("SPC_NOTIFICATIONS__SMTP__TO", r#"["me@example.com"]"#),
+            ("SPC_REFRESH__LIBRARY", "false"),
+            ("SPC_REFRESH__EMPTY_PLEX_TRASH", "true"),
+        ],
+    );
+    let smtp = config.notifications.smtp.unwrap();
+    assert_eq!(smtp.server, "25");
+    assert_eq!(smtp.port, Some(587));
+    assert_eq!(smtp.to, vec!["me@example.com".to_string()]);
+    assert!(!config.refresh.library);
+    assert!(config.refresh.empty_plex_trash);
+}
+
+#[test]
+fn whole_sections_take_toml_values() {
+    let config = load(
+        "",
+        &[
+            ("SPC_TV__URL", "http://sonarr:8989/api/"),
+            ("SPC_TV__API_KEY", "key"),
+            ("SPC_JELLYFIN__USER", "me"),
+            ("SPC_RETENTION__RETAIN_DURATION", "2d"),
+            (
+                "SPC_JELLYFIN__SERVER",
+                r#"{ url = "http://jellyfin:8096/", api_key = "1" }"#,
+            ),
+            (
+                "SPC_WEB__USERS",
+                r#"[{ name = "admin", password = "1234" }]"#,
+            ),
+            (
+                "SPC_NOTIFICATIONS__WEBHOOKS",
+                r#"[{ url = "http://hook/" }]"#,
+            ),
+            (
+                "SPC_NOTIFICATIONS__SMTP",
+                r#"{ server = "mail", from = "a@b", to = ["c@d"] }"#,
+            ),
+        ],
+    );
+    match config.viewer {
+        Viewer::Jellyfin(jellyfin) => {
+            assert_eq!(key(&jellyfin.server.api_key), "1")
+        }
+        other => panic!("unexpected viewer {:?}", other),
+    }
+    assert_eq!(config.web.users[0].name, "admin");
+    assert_eq!(
+        config.notifications.webhooks[0].url.as_str(),
+        "http://hook/"
+    );
+    assert_eq!(config.notifications.smtp.unwrap().server, "mail");
+}
+
+#[test]
+fn api_key_command_runs_only_when_resolved() {
+    let config = load(
+        "",
+        &[
+            ("SPC_TV__URL", "http://sonarr:8989/api/"),
+            ("SPC_TV__API_KEY_COMMAND", r#"["false"]"#),
+            ("SPC_PLEX__URL", "http://plex:32400/"),
+            ("SPC_PLEX__API_KEY", "key"),
+            ("SPC_RETENTION__RETAIN_DURATION", "2d"),
+        ],
+    );
+    assert!(config.tv.api_key.resolve().is_err());
+}
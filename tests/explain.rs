@@ -0,0 +1,89 @@
+mod support;
+
+use abscissa_core::testing::CmdRunner;
+use std::io::Read;
+use std::path::Path;
+use support::{
+    season, series, test_dir, write_config, FakePlex, FakeSonarr, SonarrState, WatchedSeason,
+};
+
+const LONG_AGO: &str = "2020-03-01T20:00:00Z";
+
+const RETENTION: &str = r#"
+[retention]
+retain_duration = "14d"
+"#;
+
+/// Sonarr knows about "Finished Show" (ID 1) and "1883" (ID 2), both
+/// fully watched on Plex, which also has "Other Show".
+fn servers() -> (FakeSonarr, FakePlex) {
+    let sonarr = FakeSonarr::start(SonarrState {
+        series: vec![
+            series(
+                1,
+                "Finished Show",
+                &[],
+                vec![season(1, LONG_AGO, None, 2000)],
+            ),
+            series(2, "1883", &[], vec![season(1, LONG_AGO, None, 1000)]),
+        ],
+        ..SonarrState::default()
+    });
+    let plex = FakePlex::start(vec![
+        WatchedSeason::new("Finished Show", 1, 2, 2),
+        WatchedSeason::new("1883", 1, 2, 2),
+        WatchedSeason::new("Other Show", 1, 2, 2),
+    ]);
+    (sonarr, plex)
+}
+
+fn explain(name: &str, sonarr: &FakeSonarr, plex: &FakePlex, query: &str) -> String {
+    let dir = test_dir(name);
+    let servers = format!(
+        "[tv]\nurl = {:?}\napi_key = \"sonarr-key\"\n\n[plex]\nurl = {:?}\napi_key = \"plex-key\"\n",
+        sonarr.url(),
+        plex.url()
+    );
+    let config = write_config(&dir, &servers, RETENTION);
+    run_explain(&config, query)
+}
+
+fn run_explain(config: &Path, query: &str) -> String {
+    let mut runner = CmdRunner::default();
+    runner
+        .args(["-c", config.to_str().unwrap(), "explain", query])
+        .capture_stdout();
+    let mut process = runner.run();
+    let mut output = String::new();
+    process.stdout().read_to_string(&mut output).unwrap();
+    process.wait().unwrap().expect_success();
+    output
+}
+
+#[test]
+fn finds_series_by_id() {
+    let (sonarr, plex) = servers();
+    let output = explain("explain-id", &sonarr, &plex, "1");
+    assert!(output.contains("Finished Show (sonarr ID 1)"), "{}", output);
+    assert!(output.contains("Season 1: delete"), "{}", output);
+}
+
+#[test]
+fn looks_up_numbers_that_are_no_id_as_titles() {
+    let (sonarr, plex) = servers();
+    let output = explain("explain-number-title", &sonarr, &plex, "1883");
+    assert!(output.contains("1883 (sonarr ID 2)"), "{}", output);
+    assert!(output.contains("matched show \"1883\""), "{}", output);
+}
+
+#[test]
+fn fetches_only_the_matched_show() {
+    let (sonarr, plex) = servers();
+    explain("explain-one-show", &sonarr, &plex, "Finished Show");
+    let seasons: Vec<String> = plex
+        .requests()
+        .into_iter()
+        .filter(|r| r.starts_with("GET library/metadata/"))
+        .collect();
+    assert_eq!(seasons, vec!["GET library/metadata/0/children"]);
+}
@@ -0,0 +1,35 @@
+mod support;
+
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use support::test_dir;
+
+#[test]
+fn writes_to_the_config_file_given_with_c() {
+    let dir = test_dir("init");
+    // not a valid config; `init` must not try to load it:
+    let path = dir.join("elsewhere.toml");
+    fs::write(&path, "not a config").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sonarr-plex-cleaner"))
+        .arg("-c")
+        .arg(&path)
+        .arg("init")
+        .env_remove("SPC_CONFIG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // decline to overwrite it:
+    child.stdin.take().unwrap().write_all(b"n\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("{} exists. Overwrite it?", path.display())),
+        "unexpected output: {}",
+        stdout
+    );
+    assert_eq!(fs::read_to_string(&path).unwrap(), "not a config");
+}
@@ -0,0 +1,65 @@
+mod support;
+
+use serde_json::Value;
+use std::path::Path;
+use std::process::Command;
+use support::{test_dir, write_config};
+
+/// The servers that the responses in `tests/fixtures/tv` came from.
+const SERVERS: &str = r#"
+[tv]
+url = "http://sonarr.example:8989/api/"
+api_key = "sonarr-key"
+
+[plex]
+url = "http://plex.example:32400/"
+api_key = "plex-key"
+"#;
+
+const SETTINGS: &str = r#"
+[retention]
+retain_duration = "14d"
+
+[logging]
+format = "json"
+"#;
+
+fn run_tv(config: &Path) -> (bool, Vec<Value>) {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tv");
+    let output = Command::new(env!("CARGO_BIN_EXE_sonarr-plex-cleaner"))
+        .arg("-c")
+        .arg(config)
+        .arg("tv")
+        .env("SPC_REPLAY_DIR", fixtures)
+        .env_remove("SPC_RECORD_DIR")
+        .output()
+        .unwrap();
+    let lines = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    (output.status.success(), lines)
+}
+
+#[test]
+fn dry_run_finds_watched_seasons() {
+    let dir = test_dir("replay-tv");
+    let config = write_config(&dir, SERVERS, SETTINGS);
+    let (success, lines) = run_tv(&config);
+    assert!(success);
+
+    let deletions: Vec<&Value> = lines
+        .iter()
+        .filter(|l| l["message"].as_str().unwrap_or("").starts_with("delete"))
+        .collect();
+    assert_eq!(deletions.len(), 1, "{:?}", lines);
+    assert_eq!(deletions[0]["title"], "Finished Show");
+    assert_eq!(deletions[0]["season"], 1);
+    assert_eq!(deletions[0]["service"], "plex");
+
+    let running = lines
+        .iter()
+        .find(|l| l["title"] == "Running Show")
+        .expect("no message about the running show");
+    assert_eq!(running["reason"], "still_airing");
+}
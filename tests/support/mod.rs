@@ -0,0 +1,519 @@
+//! Fake Sonarr, Plex and Jellyfin servers for integration tests.
+//!
+//! Each fake listens on a random local port and answers the
+//! endpoints that the API clients use from a state that tests set up
+//! beforehand and inspect afterwards.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Response, Server};
+
+/// A response: status, content type and body.
+type Reply = (u16, &'static str, String);
+
+const JSON: &str = "application/json; charset=utf-8";
+const XML: &str = "text/xml;charset=utf-8";
+
+fn json_reply(value: Value) -> Reply {
+    (200, JSON, value.to_string())
+}
+
+fn not_found() -> Reply {
+    (404, JSON, "{}".to_string())
+}
+
+/// An HTTP server on a local port that answers requests with a
+/// handler function on a background thread.
+struct FakeServer {
+    url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeServer {
+    /// Starts a server whose handler receives the method, the request
+    /// URL without the leading `/` (including the query) and the body.
+    fn start<F>(handle: F) -> FakeServer
+    where
+        F: Fn(&Method, &str, &str) -> Reply + Send + 'static,
+    {
+        let server = Arc::new(Server::http("127.0.0.1:0").expect("starting a fake server"));
+        let addr = server.server_addr().to_ip().expect("listening on TCP");
+        let url = format!("http://{}/", addr);
+        let requests = Arc::new(Mutex::new(vec![]));
+        let thread = {
+            let server = Arc::clone(&server);
+            let requests = Arc::clone(&requests);
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let mut body = String::new();
+                    let _ = request.as_reader().read_to_string(&mut body);
+                    let path = request.url().trim_start_matches('/').to_string();
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {}", request.method(), path));
+                    let (status, content_type, body) = handle(request.method(), &path, &body);
+                    let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+                        .expect("valid header");
+                    let _ = request.respond(
+                        Response::from_string(body)
+                            .with_status_code(status)
+                            .with_header(header),
+                    );
+                }
+            })
+        };
+        FakeServer {
+            url,
+            requests,
+            server,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl FakeServer {
+    /// Returns the requests that the server received, e.g.
+    /// `GET library/sections`.
+    fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns the Sonarr JSON for a season.
+pub fn season(number: u32, previous_airing: &str, next_airing: Option<&str>, size: u64) -> Value {
+    json!({
+        "seasonNumber": number,
+        "monitored": true,
+        "statistics": {
+            "episodeFileCount": 2,
+            "totalEpisodeCount": 2,
+            "episodeCount": 2,
+            "previousAiring": previous_airing,
+            "nextAiring": next_airing,
+            "sizeOnDisk": size,
+        }
+    })
+}
+
+/// Returns the Sonarr JSON for a series.
+pub fn series(id: u32, title: &str, tags: &[u32], seasons: Vec<Value>) -> Value {
+    json!({
+        "id": id,
+        "title": title,
+        "tags": tags,
+        "path": format!("/tv/{}", title),
+        "seasons": seasons,
+    })
+}
+
+/// Returns the Sonarr JSON for an episode file.
+pub fn episode_file(id: u32, series_id: u32, season: u32, size: u64) -> Value {
+    json!({
+        "id": id,
+        "seriesId": series_id,
+        "seasonNumber": season,
+        "path": format!("/tv/{}/{}/{}.mkv", series_id, season, id),
+        "size": size,
+    })
+}
+
+/// What a [`FakeSonarr`] knows about.
+#[derive(Debug, Default)]
+pub struct SonarrState {
+    /// Series, as returned by `GET series`.
+    pub series: Vec<Value>,
+
+    /// Tags, as returned by `GET tag`.
+    pub tags: Vec<Value>,
+
+    /// Episode files that still exist.
+    pub episode_files: Vec<Value>,
+
+    /// IDs of the episode files that were deleted, in order.
+    pub deleted_files: Vec<u32>,
+
+    /// Whether the server is Sonarr v3, with its API under
+    /// `/api/v3/`, rather than Sonarr v2.
+    pub v3: bool,
+
+    /// The requests that the server received, e.g. `GET series`.
+    pub requests: Vec<String>,
+
+    /// IDs of episode files that fail to delete, with a 500 status.
+    /// A bulk delete removes the files before the first of these.
+    pub undeletable: Vec<u32>,
+}
+
+impl SonarrState {
+    fn handle(&mut self, method: &Method, path: &str, body: &str) -> Reply {
+        let (path, query) = match path.find('?') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => (path, ""),
+        };
+        self.requests.push(format!("{} {}", method, path));
+        let parts: Vec<&str> = path.split('/').collect();
+        match (method, parts.as_slice()) {
+            (Method::Get, ["system", "status"]) => {
+                let version = if self.v3 { "3.0.10.1567" } else { "2.0.0.5344" };
+                json_reply(json!({ "version": version }))
+            }
+            (Method::Get, ["series"]) => json_reply(Value::Array(self.series.clone())),
+            (Method::Get, ["series", id]) => match self.series_index(id) {
+                Some(i) => json_reply(self.series[i].clone()),
+                None => not_found(),
+            },
+            (Method::Put, ["series", id]) => match (self.series_index(id), body.parse()) {
+                (Some(i), Ok(series)) => {
+                    self.series[i] = series;
+                    json_reply(self.series[i].clone())
+                }
+                (None, _) => not_found(),
+                (_, Err(e)) => (400, JSON, json!({"message": e.to_string()}).to_string()),
+            },
+            (Method::Get, ["tag"]) => json_reply(Value::Array(self.tags.clone())),
+            (Method::Get, ["episodefile"]) => {
+                let series_id = query
+                    .strip_prefix("seriesId=")
+                    .and_then(|id| id.parse::<u64>().ok());
+                let files = self
+                    .episode_files
+                    .iter()
+                    .filter(|f| f["seriesId"].as_u64() == series_id)
+                    .cloned()
+                    .collect();
+                json_reply(Value::Array(files))
+            }
+            (Method::Post, ["seasonpass"]) if self.v3 => {
+                let request: Value = body.parse().unwrap_or_default();
+                for update in request["series"].as_array().into_iter().flatten() {
+                    let i = match self.series_index(&update["id"].to_string()) {
+                        Some(i) => i,
+                        None => return not_found(),
+                    };
+                    for season in update["seasons"].as_array().into_iter().flatten() {
+                        let seasons = self.series[i]["seasons"].as_array_mut().unwrap();
+                        for known in seasons.iter_mut() {
+                            if known["seasonNumber"] == season["seasonNumber"] {
+                                known["monitored"] = season["monitored"].clone();
+                            }
+                        }
+                    }
+                }
+                json_reply(json!({}))
+            }
+            (Method::Delete, ["episodefile", "bulk"]) if self.v3 => {
+                let request: Value = body.parse().unwrap_or_default();
+                for id in request["episodeFileIds"].as_array().into_iter().flatten() {
+                    if self.undeletable.iter().any(|u| *id == *u) {
+                        return (500, JSON, json!({"message": "disk on fire"}).to_string());
+                    }
+                    if let Some(i) = self.episode_files.iter().position(|f| f["id"] == *id) {
+                        self.episode_files.remove(i);
+                        self.deleted_files.push(id.as_u64().unwrap() as u32);
+                    }
+                }
+                json_reply(json!({}))
+            }
+            (Method::Delete, ["episodefile", id])
+                if self.undeletable.iter().any(|u| u.to_string() == *id) =>
+            {
+                (500, JSON, json!({"message": "disk on fire"}).to_string())
+            }
+            (Method::Delete, ["episodefile", id]) => {
+                match self
+                    .episode_files
+                    .iter()
+                    .position(|f| f["id"].as_u64() == id.parse().ok())
+                {
+                    Some(i) => {
+                        self.episode_files.remove(i);
+                        self.deleted_files.push(id.parse().unwrap());
+                        json_reply(json!({}))
+                    }
+                    None => not_found(),
+                }
+            }
+            _ => not_found(),
+        }
+    }
+
+    fn series_index(&self, id: &str) -> Option<usize> {
+        self.series
+            .iter()
+            .position(|s| s["id"].as_u64() == id.parse().ok())
+    }
+
+    /// Returns the series with the given ID.
+    pub fn series_by_id(&self, id: u32) -> &Value {
+        let i = self.series_index(&id.to_string()).expect("no such series");
+        &self.series[i]
+    }
+}
+
+/// A fake Sonarr server, serving its API under `/api/`, or under
+/// `/api/v3/` if it pretends to be Sonarr v3.
+pub struct FakeSonarr {
+    server: FakeServer,
+    state: Arc<Mutex<SonarrState>>,
+}
+
+impl FakeSonarr {
+    /// Starts a server that knows about `state`.
+    pub fn start(state: SonarrState) -> FakeSonarr {
+        let prefix = if state.v3 { "api/v3/" } else { "api/" };
+        let state = Arc::new(Mutex::new(state));
+        let server = {
+            let state = Arc::clone(&state);
+            FakeServer::start(move |method, path, body| match path.strip_prefix(prefix) {
+                Some(path) => state.lock().unwrap().handle(method, path, body),
+                None => not_found(),
+            })
+        };
+        FakeSonarr { server, state }
+    }
+
+    /// Returns the URL to configure: the legacy API URL for Sonarr
+    /// v2, the plain server URL for Sonarr v3.
+    pub fn url(&self) -> String {
+        if self.state().v3 {
+            self.server.url.clone()
+        } else {
+            format!("{}api/", self.server.url)
+        }
+    }
+
+    /// Returns the server's current state.
+    pub fn state(&self) -> MutexGuard<'_, SonarrState> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// A season as a media server sees it.
+#[derive(Debug, Clone)]
+pub struct WatchedSeason {
+    /// Title of the series.
+    pub series: String,
+
+    /// Number of the season.
+    pub season: u32,
+
+    /// Number of episodes.
+    pub episodes: u32,
+
+    /// Number of episodes that were watched.
+    pub watched: u32,
+}
+
+impl WatchedSeason {
+    /// Returns a season with `watched` of `episodes` episodes watched.
+    pub fn new(series: &str, season: u32, episodes: u32, watched: u32) -> WatchedSeason {
+        WatchedSeason {
+            series: series.to_string(),
+            season,
+            episodes,
+            watched,
+        }
+    }
+}
+
+/// A fake Plex server with a single TV library, in `/tv`.
+pub struct FakePlex {
+    server: FakeServer,
+}
+
+impl FakePlex {
+    /// Starts a server with `seasons` in its TV library.
+    pub fn start(seasons: Vec<WatchedSeason>) -> FakePlex {
+        let server = FakeServer::start(move |method, path, _| {
+            let path = path.split('?').next().unwrap_or_default();
+            match (method, path) {
+                (Method::Get, "library/sections/1/refresh")
+                | (Method::Put, "library/sections/1/emptyTrash") => {
+                    return (200, XML, String::new())
+                }
+                (Method::Get, _) => {}
+                _ => return not_found(),
+            }
+            let mut shows: Vec<&str> = vec![];
+            for season in seasons.iter() {
+                if !shows.contains(&season.series.as_str()) {
+                    shows.push(&season.series);
+                }
+            }
+            let directories: String = match path {
+                "identity" => {
+                    return (
+                        200,
+                        XML,
+                        r#"<MediaContainer machineIdentifier="fake" version="1.0"/>"#.to_string(),
+                    )
+                }
+                "library/sections" => {
+                    r#"<Directory key="1" type="show" title="TV Shows"><Location id="1" path="/tv"/></Directory>"#.to_string()
+                }
+                "library/sections/1/all" => shows
+                    .iter()
+                    .enumerate()
+                    .map(|(i, title)| {
+                        format!(
+                            r#"<Directory key="/library/metadata/{}/children" ratingKey="{}" type="show" title="{}"/>"#,
+                            i, i, title
+                        )
+                    })
+                    .collect(),
+                _ => {
+                    let index = path
+                        .strip_prefix("library/metadata/")
+                        .and_then(|p| p.strip_suffix("/children"))
+                        .and_then(|i| i.parse::<usize>().ok());
+                    let (index, show) = match index.and_then(|i| Some((i, shows.get(i)?))) {
+                        Some(found) => found,
+                        None => return not_found(),
+                    };
+                    seasons
+                        .iter()
+                        .filter(|s| s.series == *show)
+                        .map(|s| {
+                            format!(
+                                r#"<Directory key="/library/metadata/{}-{}/children" parentTitle="{}" title="Season {}" type="season" leafCount="{}" viewedLeafCount="{}"/>"#,
+                                index, s.season, s.series, s.season, s.episodes, s.watched
+                            )
+                        })
+                        .collect()
+                }
+            };
+            (
+                200,
+                XML,
+                format!("<MediaContainer>{}</MediaContainer>", directories),
+            )
+        });
+        FakePlex { server }
+    }
+
+    /// Returns the URL to configure.
+    pub fn url(&self) -> String {
+        self.server.url.clone()
+    }
+
+    /// Returns the requests that the server received.
+    pub fn requests(&self) -> Vec<String> {
+        self.server.requests()
+    }
+}
+
+/// A fake Jellyfin server.
+pub struct FakeJellyfin {
+    server: FakeServer,
+}
+
+impl FakeJellyfin {
+    /// Starts a server with `users`, all of whom have watched
+    /// `seasons` to the same extent.
+    pub fn start(users: &[&str], seasons: Vec<WatchedSeason>) -> FakeJellyfin {
+        let users: Vec<Value> = users
+            .iter()
+            .enumerate()
+            .map(|(i, name)| json!({"Name": name, "Id": format!("user{}", i), "HasPassword": true}))
+            .collect();
+        let series: Vec<Value> = {
+            let mut names: Vec<&str> = vec![];
+            for season in seasons.iter() {
+                if !names.contains(&season.series.as_str()) {
+                    names.push(&season.series);
+                }
+            }
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| json!({"Name": name, "Id": format!("series{}", i)}))
+                .collect()
+        };
+        let server = FakeServer::start(move |method, path, _| {
+            let (path, query) = match path.find('?') {
+                Some(i) => (&path[..i], &path[i + 1..]),
+                None => (path, ""),
+            };
+            let parts: Vec<&str> = path.split('/').collect();
+            match (method, parts.as_slice()) {
+                (Method::Get, ["System", "Info"]) => {
+                    json_reply(json!({"ServerName": "fake", "Version": "10.8.0"}))
+                }
+                (Method::Get, ["Users"]) => json_reply(Value::Array(users.clone())),
+                (Method::Get, ["Users", _, "Items"])
+                    if query.contains("includeItemTypes=Series") =>
+                {
+                    json_reply(json!({ "Items": series }))
+                }
+                (Method::Post, ["Items", _, "Refresh"]) => (204, JSON, String::new()),
+                (Method::Get, ["Users", _, "Items"]) => {
+                    let items: Vec<Value> = seasons
+                        .iter()
+                        .map(|s| {
+                            json!({
+                                "Name": format!("Season {}", s.season),
+                                "SeriesName": s.series,
+                                "Id": format!("{}-{}", s.series, s.season),
+                                "UserData": {"UnplayedItemCount": s.episodes - s.watched},
+                                "ChildCount": s.episodes,
+                            })
+                        })
+                        .collect();
+                    json_reply(json!({ "Items": items }))
+                }
+                _ => not_found(),
+            }
+        });
+        FakeJellyfin { server }
+    }
+
+    /// Returns the URL to configure.
+    pub fn url(&self) -> String {
+        self.server.url.clone()
+    }
+
+    /// Returns the requests that the server received.
+    pub fn requests(&self) -> Vec<String> {
+        self.server.requests()
+    }
+}
+
+/// Returns a fresh directory for a test's config and audit log.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("spc-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a config file to `dir` with `servers` (the `[tv]` and
+/// viewer sections) and `extra` settings, and returns its path.
+pub fn write_config(dir: &Path, servers: &str, extra: &str) -> PathBuf {
+    let path = dir.join("config.toml");
+    let config = format!(
+        "{}\n[audit]\npath = {:?}\n\n{}\n",
+        servers,
+        dir.join("audit.jsonl"),
+        extra
+    );
+    fs::write(&path, config).unwrap();
+    path
+}